@@ -1,4 +1,5 @@
 use crate::delay_line::DelayLine;
+use log::debug;
 
 pub struct Allpass {
     gain: f32,
@@ -8,11 +9,16 @@ pub struct Allpass {
 
 impl Allpass {
     pub fn new(delay: f32, gain: f32, sample_rate: f32) -> Allpass {
-        println!("sample rate in allpass: {}", sample_rate);
-        let frames = (delay * sample_rate) as usize;
+        debug!("sample rate in allpass: {}", sample_rate);
+        Allpass::from_frames((delay * sample_rate) as usize, gain)
+    }
+
+    /// An allpass with a delay of `frames` frames, e.g. one of a few frames for a stretched
+    /// first-order allpass.
+    pub fn from_frames(frames: usize, gain: f32) -> Allpass {
         // leave a bit of slack to accomodate changes
-        let mut d_in = DelayLine::new(frames * 5);
-        let mut d_out = DelayLine::new(frames * 5);
+        let mut d_in = DelayLine::new(std::cmp::max(frames * 5, 4));
+        let mut d_out = DelayLine::new(std::cmp::max(frames * 5, 4));
         d_in.set_duration(frames);
         d_out.set_duration(frames);
         Allpass {
@@ -31,6 +37,30 @@ impl Allpass {
         self.delay_output.set_duration(delay as usize);
     }
 
+    pub fn reset(&mut self) {
+        self.delay_input.reset();
+        self.delay_output.reset();
+    }
+
+    pub fn delay(&self) -> usize {
+        self.delay_input.duration()
+    }
+
+    /// Read the output history of the allpass, `delay` frames behind the last output.
+    pub fn tap(&self, delay: f32) -> f32 {
+        self.delay_output.tap(delay)
+    }
+
+    /// Process a frame with a fractional delay that can change on each frame. An allpass
+    /// should either always be processed with this, or never.
+    pub fn process_modulated(&mut self, input: f32, delay: f32, output: &mut f32) {
+        self.delay_input.write(input);
+        let delayed_in = self.delay_input.tap(delay);
+        let delayed_out = self.delay_output.tap(delay - 1.);
+        *output = (-self.gain * input) + delayed_in + (self.gain * delayed_out);
+        self.delay_output.write(*output);
+    }
+
     pub fn process(&mut self, input: f32, output: &mut f32) {
         let mut delayed_out = 0.0;
         let mut delayed_in = 0.0;
@@ -1,63 +1,163 @@
 use crossbeam::queue::ArrayQueue;
 use cubeb::StereoFrame;
+use fdn_reverb::reverb::{self, ParameterInfo, Reverb};
 use fdn_reverb::utils::*;
-use fdn_reverb::FDNReverb;
 use monome::*;
+use std::env;
 use std::fs::read_dir;
+use std::path::Path;
+use std::process::exit;
 use std::sync::Arc;
 use std::time::Instant;
 use std::{thread, time};
 use std::io;
 
 const PROFILE: bool = false;
+// Length of the crossfade at the loop seam.
+const CROSSFADE_MS: usize = 10;
 
+// Plays the first channel of a sample up to the end of its first loop, then repeats the loop.
+// Samples without a loop, or with a loop past their end, repeat whole. The end of the loop is
+// crossfaded with its start, after which playback continues past the part of the start that was
+// faded in. Empty samples can't be played.
 struct LoopPlayer {
     sample: Sample,
     idx: usize,
+    start: usize,
+    end: usize,
+    fade: usize,
 }
 
 impl LoopPlayer {
-    fn new(sample: Sample) -> LoopPlayer {
-        LoopPlayer { sample, idx: 0 }
+    fn new(sample: Sample) -> Result<LoopPlayer, String> {
+        if sample.frames() == 0 {
+            return Err(format!("{} is empty", sample.name()));
+        }
+        let (start, end) = match sample.loops().first() {
+            Some(l) if l.start < l.end && l.end <= sample.frames() => (l.start, l.end),
+            _ => (0, sample.frames()),
+        };
+        let fade = std::cmp::min(
+            sample.rate() as usize * CROSSFADE_MS / 1000,
+            (end - start) / 2,
+        );
+        Ok(LoopPlayer {
+            sample,
+            idx: 0,
+            start,
+            end,
+            fade,
+        })
+    }
+    fn frame(&self, idx: usize) -> f32 {
+        self.sample[idx * self.sample.channels() as usize]
     }
     fn extract(&mut self, frames: &mut [f32]) {
         for s in frames.iter_mut() {
-            *s = self.sample.slice(self.idx, 1)[0];
-            self.idx = (self.idx + 1) % self.sample.frames();
+            *s = if self.idx >= self.end - self.fade {
+                // Equal gain crossfade from the end of the loop to its start: both are usually
+                // well correlated, an equal power one would bump the level.
+                let i = self.idx - (self.end - self.fade);
+                let t = (i as f32 + 0.5) / self.fade as f32;
+                self.frame(self.idx) * (1. - t) + self.frame(self.start + i) * t
+            } else {
+                self.frame(self.idx)
+            };
+            self.idx += 1;
+            if self.idx == self.end {
+                self.idx = self.start + self.fade;
+            }
         }
     }
 }
 
-enum Parameter {
-    PreDelay(f32),
-    Absorbtion(f32),
-    Size(f32),
-    Decay(f32),
-    SaturationHardness(f32),
-    Progression(f32),
-    Width(f32),
-    DryWet(f32)
+struct ParameterChange {
+    index: usize,
+    value: f32,
+}
+
+// Find a parameter of the engine by name, parameters that the engine doesn't have are ignored.
+fn change(parameters: &[ParameterInfo], name: &str, value: f32) -> Option<ParameterChange> {
+    parameters
+        .iter()
+        .position(|p| p.name == name)
+        .map(|index| ParameterChange { index, value })
+}
+
+// Map the sliders of sliderz.json to engine parameters.
+fn slider_to_parameter(idx: u32, val: u32) -> (&'static str, f32) {
+    match idx {
+        0 => ("pre-delay", val as f32),
+        1 => ("absorption", val as f32),
+        2 => ("size", val as f32 / 10.),
+        3 => ("decay", val as f32 / 100.),
+        4 => ("hardness", val as f32 / 10.),
+        5 => ("progression", 1. + val as f32 / 10.),
+        6 => ("width", val as f32 / 100.),
+        7 => ("dry/wet", val as f32 / 100.),
+        _ => {
+            panic!("ij");
+        }
+    }
 }
 
 fn main() {
-    let q = Arc::new(ArrayQueue::<Parameter>::new(32));
+    // live [engine] [impulse response]
+    let args: Vec<String> = env::args().collect();
+    let engine = args.get(1).map(String::as_str).unwrap_or("fdn");
+
+    let ctx = cubeb::init("fdn-reverb").expect("Failed to create cubeb context");
+    let rate = ctx.preferred_sample_rate().unwrap_or(48000);
+    let impulse_response = args.get(2).map(|p| {
+        Sample::load(Path::new(p))
+            .map(|s| s.resampled(rate))
+            .unwrap_or_else(|e| {
+                eprintln!("can't load {}: {}", p, e);
+                exit(1);
+            })
+    });
+
+    let q = Arc::new(ArrayQueue::<ParameterChange>::new(32));
     let q2 = q.clone();
     let paths = read_dir("samples").unwrap();
 
     let mut samples: Vec<Sample> = Vec::new();
 
     for path in paths {
-        let path = path.unwrap();
-        samples.push(Sample::new(&path));
+        let path = path.unwrap().path();
+        match Sample::load(&path).map(|s| s.resampled(rate)) {
+            Ok(s) => samples.push(s),
+            Err(e) => eprintln!("skipping {}: {}", path.display(), e),
+        }
     }
 
-    let s = samples.pop().unwrap();
-    let rate = 48000;
-    let mut loop_player = LoopPlayer::new(s);
+    let s = samples.pop().unwrap_or_else(|| {
+        eprintln!("no sample to play in samples/");
+        exit(1);
+    });
+    if let Some(l) = s.loops().first() {
+        println!("{}: looping frames {} to {}", s.name(), l.start, l.end);
+    }
+    for cue in s.cues() {
+        println!(
+            "{}: cue at frame {} {}",
+            s.name(),
+            cue.position,
+            cue.label.as_deref().unwrap_or("")
+        );
+    }
+    let mut loop_player = LoopPlayer::new(s).unwrap_or_else(|e| {
+        eprintln!("can't play {}", e);
+        exit(1);
+    });
 
-    let mut reverb = FDNReverb::new(rate as f32);
+    let mut reverb: Box<dyn Reverb> =
+        reverb::create(engine, rate as f32, impulse_response.as_ref()).unwrap();
+    let parameters = reverb.parameters();
+    for (i, p) in parameters.iter().enumerate() {
+        println!("{}: {} [{}, {}] {}", i, p.name, p.min, p.max, p.unit);
+    }
 
-    let ctx = cubeb::init("fdn-reverb").expect("Failed to create cubeb context");
     let params = cubeb::StreamParamsBuilder::new()
         .format(cubeb::SampleFormat::Float32NE)
         .rate(rate)
@@ -78,42 +178,9 @@ fn main() {
         .latency(256)
         .data_callback(
             move |_input: &[StereoFrame<f32>], mut output: &mut [StereoFrame<f32>]| {
-                match q2.pop() {
-                    Ok(msg) => match msg {
-                        Parameter::PreDelay(v) => {
-                            println!("set pre-delay to {}", v);
-                            reverb.set_pre_delay(v);
-                        }
-                        Parameter::Width(v) => {
-                            println!("set width to {}", v);
-                            reverb.set_width(v);
-                        }
-                        Parameter::Absorbtion(v) => {
-                            println!("set abs to {}", v);
-                            reverb.set_absorbtion(v);
-                        }
-                        Parameter::Size(v) => {
-                            println!("set size to {}", v);
-                            reverb.set_size(v / 10.);
-                        }
-                        Parameter::Decay(v) => {
-                            println!("set decay to {}", v);
-                            reverb.set_decay(v);
-                        }
-                        Parameter::SaturationHardness(v) => {
-                            println!("set hardness to {}", v);
-                            reverb.set_hardness(v);
-                        }
-                        Parameter::Progression(v) => {
-                            println!("set progression to {}", v);
-                            reverb.set_progression(v);
-                        }
-                        Parameter::DryWet(v) => {
-                            println!("set drywet to {}", v);
-                            reverb.set_drywet(v);
-                        }
-                    },
-                    Err(crossbeam::queue::PopError) => {}
+                if let Ok(change) = q2.pop() {
+                    println!("set {} to {}", parameters[change.index].name, change.value);
+                    reverb.set_parameter(change.index, change.value);
                 }
                 let start = Instant::now();
                 pcm.resize(output.len(), 0.0);
@@ -135,7 +202,7 @@ fn main() {
                     let avg_duration_us = (duration as f32 / callback_count as f32) / 1000.;
                     let avg_duration_per_sample =
                         (duration as f32 / callback_count as f32) / 1000. / 512.;
-                    let budget_us = 512. / 48000. * 1000. * 1000.;
+                    let budget_us = 512. / rate as f32 * 1000. * 1000.;
                     println!(
                         "{}us ({} per sample, dsp load: {})",
                         avg_duration_us,
@@ -181,16 +248,18 @@ fn main() {
                             if led[n] > 56. {
                                 led[n] = 56.;
                             }
-                            let msg = match n {
-                                0 => Parameter::Absorbtion((led[n] - 8.) / 48. * 100.),
-                                1 => Parameter::Size((led[n] - 8.) / 48. * 1000.),
-                                2 => Parameter::Decay((led[n] - 8.) / 48. * 1.25),
-                                3 => Parameter::DryWet((led[n] - 8.) / 48.),
+                            let (name, value) = match n {
+                                0 => ("absorption", (led[n] - 8.) / 48. * 100.),
+                                1 => ("size", (led[n] - 8.) / 48. * 100.),
+                                2 => ("decay", (led[n] - 8.) / 48. * 1.25),
+                                3 => ("dry/wet", (led[n] - 8.) / 48.),
                                 _ => {
                                     panic!("ij");
                                 }
                             };
-                            q.push(msg).unwrap();
+                            if let Some(msg) = change(parameters, name, value) {
+                                q.push(msg).unwrap();
+                            }
                             monome.ring_set(n, led[n] as u32, 15);
                         }
                         _ => {
@@ -214,20 +283,10 @@ fn main() {
                 let v : Vec<&str> = sp.collect();
                 if let Ok(idx) = v[0].parse::<u32>() {
                     if let Ok(val) = v[1].parse::<u32>() {
-                        let msg = match idx {
-                            0 => Parameter::PreDelay(val as f32),
-                            1 => Parameter::Absorbtion(val as f32),
-                            2 => Parameter::Size(val as f32),
-                            3 => Parameter::Decay(val as f32 / 100.),
-                            4 => Parameter::SaturationHardness(val as f32 / 10.),
-                            5 => Parameter::Progression(1. + val as f32 / 10.),
-                            6 => Parameter::Width(val as f32 / 100.),
-                            7 => Parameter::DryWet(val as f32 / 100.),
-                            _ => {
-                                panic!("ij");
-                            }
-                        };
-                        q.push(msg).unwrap();
+                        let (name, value) = slider_to_parameter(idx, val);
+                        if let Some(msg) = change(parameters, name, value) {
+                            q.push(msg).unwrap();
+                        }
                     }
                 }
 
@@ -1,49 +1,1206 @@
+use fdn_reverb::preset::Preset;
+use fdn_reverb::quantize::{NoiseShaping, Quantizer};
+use fdn_reverb::resample::Resampler;
+use fdn_reverb::reverb::{self, Reverb};
 use fdn_reverb::utils::*;
-use fdn_reverb::FDNReverb;
-use std::fs::read_dir;
+use fdn_reverb::wav::{SampleFormat, WavSpec, WavWriter};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
 const BLOCK_SIZE: usize = 32;
 
-fn main() {
-    let paths = read_dir("samples").unwrap();
+// Extensions of the files picked up when processing a directory.
+const EXTENSIONS: &[&str] = &["wav", "wave", "flac", "ogg", "caf"];
+
+const USAGE: &str = "usage: process [options] <input> [output]
+
+Renders <input> through a reverb and writes the result to [output] (default: out.wav).
+
+If <input> is a directory, or a pattern such as `sfx/**/*.wav` (`*` and `?` match within a
+name, `**` matches any number of directories), all the matching audio files are rendered, and
+written as WAV files with the same relative paths under the directory [output] (default: out).
+Files that only differ by their extension keep it: `a.wav` and `a.flac` give `a.wav.wav` and
+`a.flac.wav`.
+
+With --raw, raw interleaved PCM is read from the standard input and written to the standard
+output, as it comes, followed by the tail: there is no <input> or [output].
+
+options:
+  -e, --engine <name>         reverb engine (default: fdn, or the engine of the preset)
+      --ir <file>             impulse response, for the convolution engines
+  -p, --preset <file>         preset file, one `name = value` per line
+  -s, --set <name>=<value>    set a parameter, after the preset (can be repeated)
+      --list                  list the parameters of the engine and exit
+      --mono-in               downmix the input and use the mono input of the engine, instead
+                              of its stereo input for files with two channels or more
+      --mono-out              downmix the output to mono, instead of writing it in stereo
+      --wet                   only write the wet signal
+      --dry-wet               write the dry and wet signals mixed by the engine (default)
+  -b, --bits <16|24|32|f32>   output sample format (default: 16)
+      --no-dither             round integer output instead of dithering it
+      --noise-shaping <none|first|lipshitz>
+                              shape the dither noise (default: none)
+      --tail <seconds>        render a fixed length after the end of the input, instead of
+                              rendering until the output is silent
+      --silence <dBFS>        level under which the output is silent (default: -90)
+      --silence-hold <ms>     time the output has to stay silent to stop (default: 500)
+      --max-tail <seconds>    longest tail rendered after the end of the input (default: 60)
+      --fade <ms>             fade the end of the output out (default: 0)
+      --oversample <factor>   run the engine at 2, 4 or 8 times the rate of the input, and
+                              convert the output back (default: 1)
+      --reverse               reverse reverb: the tail swells up to the dry signal
+      --align <ms>            with --reverse, delay of the dry signal after the swell
+      --raw <f32|s16>         filter raw little-endian PCM from the standard input to the
+                              standard output, in this format
+      --rate <Hz>             with --raw, sample rate of the input
+      --channels <1|2>        with --raw, channel count of the input (default: 2)
+  -j, --jobs <count>          number of files rendered in parallel (default: one per core)
+  -h, --help                  print this help";
+
+struct Options {
+    input: PathBuf,
+    output: Option<PathBuf>,
+    engine: Option<String>,
+    impulse_response: Option<PathBuf>,
+    preset: Option<PathBuf>,
+    parameters: Vec<(String, f32)>,
+    list: bool,
+    mono_in: bool,
+    mono_out: bool,
+    wet_only: bool,
+    format: SampleFormat,
+    dither: bool,
+    shaping: NoiseShaping,
+    tail: Option<f32>,
+    silence: f32,
+    silence_hold: f32,
+    max_tail: f32,
+    fade: f32,
+    reverse: bool,
+    align: f32,
+    jobs: usize,
+    oversample: u32,
+    raw: Option<RawFormat>,
+    rate: Option<u32>,
+    channels: usize,
+}
+
+#[derive(Clone, Copy)]
+enum RawFormat {
+    F32,
+    S16,
+}
+
+impl RawFormat {
+    fn bytes(self) -> usize {
+        match self {
+            RawFormat::F32 => 4,
+            RawFormat::S16 => 2,
+        }
+    }
+}
+
+fn parse_format(value: &str) -> Result<SampleFormat, String> {
+    match value {
+        "16" => Ok(SampleFormat::Int16),
+        "24" => Ok(SampleFormat::Int24),
+        "32" => Ok(SampleFormat::Int32),
+        "f32" | "float" => Ok(SampleFormat::Float32),
+        _ => Err(format!("unknown sample format `{}`", value)),
+    }
+}
+
+fn parse_shaping(value: &str) -> Result<NoiseShaping, String> {
+    match value {
+        "none" => Ok(NoiseShaping::None),
+        "first" => Ok(NoiseShaping::FirstOrder),
+        "lipshitz" => Ok(NoiseShaping::Lipshitz),
+        _ => Err(format!("unknown noise shaping `{}`", value)),
+    }
+}
+
+fn parse_number(option: &str, value: &str) -> Result<f32, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} expects a number, got `{}`", option, value))
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut positional = Vec::new();
+    let mut options = Options {
+        input: PathBuf::new(),
+        output: None,
+        engine: None,
+        impulse_response: None,
+        preset: None,
+        parameters: Vec::new(),
+        list: false,
+        mono_in: false,
+        mono_out: false,
+        wet_only: false,
+        format: SampleFormat::Int16,
+        dither: true,
+        shaping: NoiseShaping::None,
+        tail: None,
+        silence: -90.,
+        silence_hold: 500.,
+        max_tail: 60.,
+        fade: 0.,
+        reverse: false,
+        align: 0.0,
+        jobs: thread::available_parallelism().map_or(1, |n| n.get()),
+        oversample: 1,
+        raw: None,
+        rate: None,
+        channels: 2,
+    };
+    while let Some(arg) = args.next() {
+        // Accept both `--option value` and `--option=value`.
+        let (option, inline) = match arg.find('=') {
+            Some(i) if arg.starts_with("--") => {
+                (arg[..i].to_string(), Some(arg[i + 1..].to_string()))
+            }
+            _ => (arg.clone(), None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} expects a value", option))
+        };
+        match option.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            "-e" | "--engine" => options.engine = Some(value()?),
+            "--ir" => options.impulse_response = Some(PathBuf::from(value()?)),
+            "-p" | "--preset" => options.preset = Some(PathBuf::from(value()?)),
+            "-s" | "--set" => {
+                let assignment = value()?;
+                let mut split = assignment.splitn(2, '=');
+                let name = split.next().unwrap().trim().to_string();
+                let number = split
+                    .next()
+                    .ok_or_else(|| format!("{} expects <name>=<value>", option))?;
+                let number = parse_number(&name, number.trim())?;
+                options.parameters.push((name, number));
+            }
+            "--list" => options.list = true,
+            "--mono-in" => options.mono_in = true,
+            "--mono-out" => options.mono_out = true,
+            "--wet" => options.wet_only = true,
+            "--dry-wet" => options.wet_only = false,
+            "-b" | "--bits" => options.format = parse_format(&value()?)?,
+            "--no-dither" => options.dither = false,
+            "--noise-shaping" => options.shaping = parse_shaping(&value()?)?,
+            "--tail" => options.tail = Some(parse_number(&option, &value()?)?),
+            "--silence" => options.silence = parse_number(&option, &value()?)?,
+            "--silence-hold" => options.silence_hold = parse_number(&option, &value()?)?,
+            "--max-tail" => options.max_tail = parse_number(&option, &value()?)?,
+            "--fade" => options.fade = parse_number(&option, &value()?)?,
+            "--reverse" => options.reverse = true,
+            "--align" => options.align = parse_number(&option, &value()?)?,
+            "--raw" => {
+                options.raw = match value()?.as_str() {
+                    "f32" => Some(RawFormat::F32),
+                    "s16" => Some(RawFormat::S16),
+                    other => return Err(format!("unknown raw format `{}`", other)),
+                }
+            }
+            "--rate" => {
+                let rate = parse_number(&option, &value()?)?;
+                if rate.is_nan() || rate < 1. {
+                    return Err(format!(
+                        "--rate expects a positive sample rate, got {}",
+                        rate
+                    ));
+                }
+                options.rate = Some(rate as u32);
+            }
+            "--channels" => {
+                options.channels = match value()?.as_str() {
+                    "1" => 1,
+                    "2" => 2,
+                    other => return Err(format!("--channels expects 1 or 2, got `{}`", other)),
+                }
+            }
+            "--oversample" => {
+                options.oversample = match value()?.as_str() {
+                    "1" => 1,
+                    "2" => 2,
+                    "4" => 4,
+                    "8" => 8,
+                    other => {
+                        return Err(format!(
+                            "--oversample expects 1, 2, 4 or 8, got `{}`",
+                            other
+                        ))
+                    }
+                }
+            }
+            "-j" | "--jobs" => {
+                options.jobs = std::cmp::max(1, parse_number(&option, &value()?)? as usize)
+            }
+            _ if option.starts_with('-') && option.len() > 1 => {
+                return Err(format!("unknown option `{}`", option));
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    match positional.next() {
+        Some(input) => options.input = PathBuf::from(input),
+        None if options.list || options.raw.is_some() => {}
+        None => return Err("missing input file".to_string()),
+    }
+    if let Some(output) = positional.next() {
+        options.output = Some(PathBuf::from(output));
+    }
+    if let Some(extra) = positional.next() {
+        return Err(format!("unexpected argument `{}`", extra));
+    }
+    if options.raw.is_some() {
+        if options.reverse {
+            return Err("--reverse needs the whole input, it can't be used with --raw".to_string());
+        }
+        if options.oversample != 1 {
+            return Err("--oversample can't be used with --raw".to_string());
+        }
+        if options.rate.is_none() {
+            return Err("--raw needs --rate".to_string());
+        }
+        if !options.input.as_os_str().is_empty() || options.output.is_some() {
+            return Err(
+                "--raw reads from the standard input and writes to the standard output".to_string(),
+            );
+        }
+    }
+    Ok(options)
+}
 
-    let mut samples: Vec<Sample> = Vec::new();
+// Returns the number of samples that clipped.
+fn write_output(
+    options: &Options,
+    path: &Path,
+    samples: &[f32],
+    channels: u32,
+    sample_rate: u32,
+) -> io::Result<usize> {
+    let spec = WavSpec {
+        channels: channels as u16,
+        sample_rate,
+        format: options.format,
+    };
+    let mut writer = WavWriter::create(path, spec)?;
+    if let Some(quantizer) = writer.quantizer_mut() {
+        quantizer.set_dither(options.dither);
+        quantizer.set_noise_shaping(options.shaping);
+    }
+    writer.write_samples(samples)?;
+    let clipped = writer.quantizer_mut().map_or(0, |q| q.clipped());
+    writer.finalize()?;
+    Ok(clipped)
+}
 
-    for path in paths {
-        let path = path.unwrap();
-        samples.push(Sample::new(&path));
+fn downmix(s: &Sample) -> Vec<f32> {
+    let mut mono = vec![0.0; s.frames()];
+    for c in 0..s.channels() as usize {
+        for (m, v) in mono.iter_mut().zip(s.channel(c)) {
+            *m += v / s.channels() as f32;
+        }
     }
+    mono
+}
 
-    let s = &samples[0];
-    let mut output_pcm = Vec::<i16>::with_capacity(s.frames());
-    output_pcm.resize((s.frames() as f32 * 1.5) as usize, 0);
+// Interleaved stereo input. Files with more than two channels are folded down: even channels go
+// to the left, odd channels to the right.
+fn stereo_input(s: &Sample) -> Vec<f32> {
+    let channels = s.channels() as usize;
+    let mut stereo = vec![0.0; s.frames() * 2];
+    let per_side = [channels.div_ceil(2), channels / 2];
+    for c in 0..channels {
+        let side = c % 2;
+        let gain = 1. / per_side[side] as f32;
+        for (o, v) in stereo.chunks_mut(2).zip(s.channel(c)) {
+            o[side] += v * gain;
+        }
+    }
+    stereo
+}
 
-    let mut reverb = FDNReverb::new(s.rate() as f32);
+// How long to render after the end of the input.
+enum Tail {
+    Fixed(usize),
+    // Until the output has stayed under `threshold` for `hold` frames, for at most `max` frames.
+    UntilSilent {
+        threshold: f32,
+        hold: usize,
+        max: usize,
+    },
+}
 
-    let mut i: usize = 0;
-    let mut output = Vec::<f32>::with_capacity(BLOCK_SIZE);
-    output.resize(BLOCK_SIZE, 0.);
-    let mut j = 0;
+// Renders `input`, mono or interleaved stereo, and its tail. The output is interleaved stereo.
+// Also returns whether the tail was cut before reaching silence.
+fn render(
+    reverb: &mut dyn Reverb,
+    input: &[f32],
+    input_channels: usize,
+    tail: Tail,
+) -> (Vec<f32>, bool) {
+    let input_frames = input.len() / input_channels;
+    let mut output_pcm = vec![0.0; input_frames * 2];
+    for (i, o) in input
+        .chunks(BLOCK_SIZE * input_channels)
+        .zip(output_pcm.chunks_mut(BLOCK_SIZE * 2))
+    {
+        if input_channels == 1 {
+            reverb.process(i, o);
+        } else {
+            reverb.process_stereo(i, o);
+        }
+    }
+
+    let tail_cut = render_tail(reverb, input_channels, tail, |f| {
+        output_pcm.extend_from_slice(f)
+    });
+    (output_pcm, tail_cut)
+}
+
+// Renders the tail, passing blocks of interleaved stereo output to `output`. Returns whether the
+// tail was cut before reaching silence.
+fn render_tail<F: FnMut(&[f32])>(
+    reverb: &mut dyn Reverb,
+    input_channels: usize,
+    tail: Tail,
+    mut output: F,
+) -> bool {
+    let (threshold, hold, max) = match tail {
+        Tail::Fixed(frames) => (f32::INFINITY, frames, frames),
+        Tail::UntilSilent {
+            threshold,
+            hold,
+            max,
+        } => (threshold, hold, max),
+    };
+    let silence = [0.0; BLOCK_SIZE * 2];
+    let mut block = [0.0; BLOCK_SIZE * 2];
+    let mut tail_frames = 0;
+    let mut quiet = 0;
+    while tail_frames < max && quiet < hold {
+        let mut frames = std::cmp::min(BLOCK_SIZE, max - tail_frames);
+        if input_channels == 1 {
+            reverb.process(&silence[..frames], &mut block[..frames * 2]);
+        } else {
+            reverb.process_stereo(&silence[..frames * 2], &mut block[..frames * 2]);
+        }
+        for (i, f) in block[..frames * 2].chunks(2).enumerate() {
+            if f[0].abs() < threshold && f[1].abs() < threshold {
+                quiet += 1;
+                if quiet >= hold {
+                    frames = i + 1;
+                    break;
+                }
+            } else {
+                quiet = 0;
+            }
+        }
+        output(&block[..frames * 2]);
+        tail_frames += frames;
+    }
+    quiet < hold && threshold.is_finite()
+}
+
+// Fades the last `frames` frames of an interleaved signal out, with a half cosine.
+fn fade_out(samples: &mut [f32], channels: usize, frames: usize) {
+    let total = samples.len() / channels;
+    let frames = std::cmp::min(frames, total);
+    let start = (total - frames) * channels;
+    for (i, f) in samples[start..].chunks_mut(channels).enumerate() {
+        let gain = 0.5 + 0.5 * (std::f32::consts::PI * (i + 1) as f32 / frames as f32).cos();
+        f.iter_mut().for_each(|s| *s *= gain);
+    }
+}
+
+// Reverse reverb: the input, `input_channels` interleaved, is reversed and rendered through
+// the reverb, and the wet signal is reversed back, so that it swells up to the dry onset. `tail`
+// is the length of the swell in seconds, and `align` moves the dry signal, in ms, after the end
+// of the swell (or before if negative, to overlap them). The dry and wet signals are mixed with
+// the dry/wet of the engine.
+fn render_reverse(
+    reverb: &mut dyn Reverb,
+    input: &[f32],
+    input_channels: usize,
+    tail: f32,
+    align: f32,
+) -> Vec<f32> {
+    let rate = reverb.sample_rate();
+    let tail_frames = (tail * rate) as usize;
+    let input_frames = input.len() / input_channels;
+
+    // The engine renders the wet signal only, the dry signal is mixed in once aligned.
+    let drywet = reverb.parameter_index("dry/wet");
+    let mix = drywet.map_or(1.0, |index| reverb.parameter(index));
+    if let Some(index) = drywet {
+        reverb.set_parameter(index, 1.0);
+    }
+    let mut reversed: Vec<f32> = input
+        .chunks(input_channels)
+        .rev()
+        .flat_map(|f| f.iter().cloned())
+        .collect();
+    reversed.resize((input_frames + tail_frames) * input_channels, 0.0);
+    let mut wet = vec![0.0; (input_frames + tail_frames) * 2];
+    for (i, o) in reversed
+        .chunks(BLOCK_SIZE * input_channels)
+        .zip(wet.chunks_mut(BLOCK_SIZE * 2))
+    {
+        if input_channels == 1 {
+            reverb.process(i, o);
+        } else {
+            reverb.process_stereo(i, o);
+        }
+    }
+    if let Some(index) = drywet {
+        reverb.set_parameter(index, mix);
+    }
+    // reverse the frames, keeping the channels in order
+    let mut frames: Vec<[f32; 2]> = wet.chunks(2).map(|f| [f[0], f[1]]).collect();
+    frames.reverse();
+
+    // The reversed dry onset excites the reverb at frame `input_frames - 1`: once reversed
+    // back, the swell ends `tail_frames` frames in.
+    let offset = tail_frames as isize + (align * rate / 1000.) as isize;
+    let start = std::cmp::min(offset, 0);
+    let end = std::cmp::max(frames.len() as isize, offset + input_frames as isize);
+    let mut output_pcm = Vec::<f32>::with_capacity((end - start) as usize * 2);
+    for f in start..end {
+        let dry = if f >= offset && f < offset + input_frames as isize {
+            let frame = (f - offset) as usize * input_channels;
+            [input[frame], input[frame + input_channels - 1]]
+        } else {
+            [0.0; 2]
+        };
+        let wet = if f >= 0 && f < frames.len() as isize {
+            frames[f as usize]
+        } else {
+            [0.0; 2]
+        };
+        for (d, w) in dry.iter().zip(wet.iter()) {
+            output_pcm.push(d * (1. - mix) + w * mix);
+        }
+    }
+    output_pcm
+}
+
+// What happened to a rendered file.
+struct Report {
+    peak: f32,
+    clipped: usize,
+    tail_cut: bool,
+}
+
+// Sets the preset, the parameters of the command line and the mix on an engine.
+fn configure(reverb: &mut dyn Reverb, presets: &[Preset], wet_only: bool) -> Result<(), String> {
+    for p in presets.iter() {
+        p.apply(reverb)
+            .map_err(|e| format!("{} for engine `{}`", e, reverb.name()))?;
+    }
+    if wet_only {
+        if let Some(index) = reverb.parameter_index("dry/wet") {
+            reverb.set_parameter(index, 1.0);
+        }
+    }
+    reverb.settle();
+    Ok(())
+}
+
+fn tail_options(options: &Options, rate: f32) -> Tail {
+    match options.tail {
+        Some(tail) => Tail::Fixed((tail * rate) as usize),
+        None => Tail::UntilSilent {
+            threshold: 10.0f32.powf(options.silence / 20.),
+            // A hold of 0 stops at the first silent frame.
+            hold: std::cmp::max((options.silence_hold * rate / 1000.) as usize, 1),
+            max: (options.max_tail * rate) as usize,
+        },
+    }
+}
+
+// Fills `buffer` from `input`, unless the end of the input comes first. Returns the number of
+// bytes read.
+fn read_block<R: Read>(input: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match input.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+// Filters raw PCM from the standard input to the standard output, block by block. Returns
+// whether the tail was cut before reaching silence.
+fn process_raw(reverb: &mut dyn Reverb, options: &Options, format: RawFormat) -> io::Result<bool> {
+    let rate = options.rate.unwrap() as f32;
+    if reverb.sample_rate() != rate {
+        reverb.set_sample_rate(rate);
+    }
+    let input_channels = options.channels;
+    let output_channels = if options.mono_out { 1 } else { 2 };
+    let frames = BLOCK_SIZE * 32;
+    let mut bytes_in = vec![0u8; frames * input_channels * format.bytes()];
+    let mut samples_in = vec![0.0; frames * input_channels];
+    let mut samples_out = vec![0.0; frames * 2];
+    let mut bytes_out = Vec::with_capacity(frames * 2 * format.bytes());
+    let mut quantizer = Quantizer::new(16, output_channels);
+    quantizer.set_dither(options.dither);
+    quantizer.set_noise_shaping(options.shaping);
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut input = stdin.lock();
+    let mut output = stdout.lock();
+    let mut write = |stereo: &[f32], output: &mut io::StdoutLock| -> io::Result<()> {
+        bytes_out.clear();
+        for f in stereo.chunks(2) {
+            let frame = [f[0], f[1]];
+            let mono = [(f[0] + f[1]) / 2.];
+            let frame: &[f32] = if output_channels == 1 { &mono } else { &frame };
+            for (c, s) in frame.iter().enumerate() {
+                match format {
+                    RawFormat::F32 => bytes_out.extend_from_slice(&s.to_le_bytes()),
+                    RawFormat::S16 => {
+                        let s = quantizer.process_sample(c, *s) as i16;
+                        bytes_out.extend_from_slice(&s.to_le_bytes())
+                    }
+                }
+            }
+        }
+        output.write_all(&bytes_out)
+    };
 
     loop {
-        if j == output_pcm.len() {
+        let read = read_block(&mut input, &mut bytes_in)?;
+        // A truncated frame at the end is dropped.
+        let count = read / format.bytes() / input_channels;
+        if count == 0 {
+            break;
+        }
+        let samples = &mut samples_in[..count * input_channels];
+        for (s, b) in samples.iter_mut().zip(bytes_in.chunks(format.bytes())) {
+            *s = match format {
+                RawFormat::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+                RawFormat::S16 => i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.,
+            };
+        }
+        let stereo = &mut samples_out[..count * 2];
+        if input_channels == 1 {
+            reverb.process(samples, stereo);
+        } else {
+            reverb.process_stereo(samples, stereo);
+        }
+        write(stereo, &mut output)?;
+        if read < bytes_in.len() {
             break;
         }
-        let mut input = s.slice(i, BLOCK_SIZE);
-        i += input.len();
-        if input.len() == 0 {
-            input = &[0.0; 128];
+    }
+
+    let mut result = Ok(());
+    let tail_cut = render_tail(reverb, input_channels, tail_options(options, rate), |f| {
+        if result.is_ok() {
+            result = write(f, &mut output);
         }
-        output.resize(input.len(), 0.);
-        reverb.process(&input, &mut output);
-        for (i, o) in input.iter().zip(output.iter()) {
-            let sample: i16 = ((*i + *o * 1.) * (2 << 14) as f32) as i16;
-            output_pcm[j] = sample;
-            j += 1;
-            if j == output_pcm.len() {
-                break;
+    });
+    result?;
+    output.flush()?;
+    Ok(tail_cut)
+}
+
+// Renders `input` to `output` with a configured engine, which is reset first.
+fn process_file(
+    reverb: &mut dyn Reverb,
+    options: &Options,
+    input: &Path,
+    output: &Path,
+) -> Result<Report, String> {
+    if !input.is_file() {
+        return Err(format!("{} is not a file", input.display()));
+    }
+    let s = Sample::load(input).map_err(|e| format!("can't load {}: {}", input.display(), e))?;
+    let output_rate = s.rate();
+    // The engine runs at the oversampled rate, and the output is converted back.
+    let s = s.resampled(output_rate * options.oversample);
+    let rate = s.rate() as f32;
+    if reverb.sample_rate() != rate {
+        reverb.set_sample_rate(rate);
+    }
+    reverb.reset();
+
+    let tail = tail_options(options, rate);
+
+    let (input_pcm, input_channels) = if options.mono_in || s.channels() == 1 {
+        (downmix(&s), 1)
+    } else {
+        (stereo_input(&s), 2)
+    };
+    let (mut output_pcm, tail_cut) = if options.reverse {
+        // The swell has to be long enough to reach the dry signal.
+        let tail = options.tail.unwrap_or(reverb.tail_size() as f32 / rate);
+        let output_pcm = render_reverse(reverb, &input_pcm, input_channels, tail, options.align);
+        (output_pcm, false)
+    } else {
+        render(reverb, &input_pcm, input_channels, tail)
+    };
+    if options.oversample != 1 {
+        output_pcm = Resampler::new(s.rate(), output_rate).process(&output_pcm, 2);
+    }
+    let fade = options.fade * output_rate as f32 / 1000.;
+    fade_out(&mut output_pcm, 2, fade as usize);
+    let output_pcm = if options.mono_out {
+        output_pcm.chunks(2).map(|f| (f[0] + f[1]) / 2.).collect()
+    } else {
+        output_pcm
+    };
+    let channels = if options.mono_out { 1 } else { 2 };
+    let peak = output_pcm.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+    let clipped = write_output(options, output, &output_pcm, channels, output_rate)
+        .map_err(|e| format!("can't write {}: {}", output.display(), e))?;
+    Ok(Report {
+        peak,
+        clipped,
+        tail_cut,
+    })
+}
+
+// Matches a name against a pattern with `*` and `?`.
+fn wildcard(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| wildcard(rest, &name[i..])),
+        Some((p, rest)) => match name.split_first() {
+            Some((n, name)) if *p == '?' || p == n => wildcard(rest, name),
+            _ => false,
+        },
+    }
+}
+
+// Matches path components against pattern components, where `**` matches any number of
+// components.
+fn matches(pattern: &[String], path: &[String]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((p, rest)) if p == "**" => (0..=path.len()).any(|i| matches(rest, &path[i..])),
+        Some((p, rest)) => match path.split_first() {
+            Some((c, path)) => {
+                let p: Vec<char> = p.chars().collect();
+                let c: Vec<char> = c.chars().collect();
+                wildcard(&p, &c) && matches(rest, path)
+            }
+            None => false,
+        },
+    }
+}
+
+// All the files under `directory`, relative to it, sorted.
+fn walk(directory: &Path, relative: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(directory.join(relative))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = relative.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            walk(directory, &path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Expands a directory or a pattern into a base directory, and the audio files under it, relative
+// to it.
+fn collect_inputs(input: &Path) -> io::Result<(PathBuf, Vec<PathBuf>)> {
+    let mut base = PathBuf::new();
+    let mut pattern = Vec::new();
+    for c in input.iter() {
+        let c = c.to_string_lossy();
+        if pattern.is_empty() && !c.contains(['*', '?']) {
+            base.push(c.as_ref());
+        } else {
+            pattern.push(c.into_owned());
+        }
+    }
+    if pattern.is_empty() {
+        pattern.push("**".to_string());
+        pattern.push("*".to_string());
+    }
+    let mut files = Vec::new();
+    walk(&base, Path::new(""), &mut files)?;
+    files.retain(|f| {
+        let components: Vec<String> = f.iter().map(|c| c.to_string_lossy().into_owned()).collect();
+        let audio = f
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .is_some_and(|e| EXTENSIONS.contains(&e.as_str()));
+        audio && matches(&pattern, &components)
+    });
+    Ok((base, files))
+}
+
+// Where the renders of `files` go under `output`: the same path with a `.wav` extension, or
+// with `.wav` appended when that would put two files in the same place (`a.wav` and `a.flac`).
+fn output_paths(output: &Path, files: &[PathBuf]) -> Vec<PathBuf> {
+    let mut count = HashMap::new();
+    for file in files {
+        *count.entry(file.with_extension("wav")).or_insert(0) += 1;
+    }
+    files
+        .iter()
+        .map(|file| {
+            let path = file.with_extension("wav");
+            if count[&path] > 1 {
+                let mut name = file.as_os_str().to_owned();
+                name.push(".wav");
+                output.join(name)
+            } else {
+                output.join(path)
+            }
+        })
+        .collect()
+}
+
+// Renders all the files, spread over `options.jobs` workers with an engine each, and prints a
+// summary. Returns whether all the files were rendered.
+fn process_batch(
+    options: &Options,
+    presets: &[Preset],
+    engine: &str,
+    impulse_response: Option<&Sample>,
+) -> bool {
+    let (base, files) = collect_inputs(&options.input)
+        .unwrap_or_else(|e| fail(&format!("can't read {}: {}", options.input.display(), e)));
+    if files.is_empty() {
+        fail(&format!("no audio file in {}", options.input.display()));
+    }
+    let output = options
+        .output
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("out"));
+    if let (Ok(a), Ok(b)) = (fs::canonicalize(&base), fs::canonicalize(&output)) {
+        if a == b {
+            fail("the output directory is the input directory");
+        }
+    }
+
+    let output_paths = output_paths(&output, &files);
+    // The engines are created and configured before the workers start, so that a bad preset
+    // fails once, here.
+    let reverbs: Vec<Box<dyn Reverb>> = (0..std::cmp::min(options.jobs, files.len()))
+        .map(|_| {
+            let mut reverb = reverb::create(engine, 44100., impulse_response)
+                .map_err(|e| format!("can't create engine `{}`: {:?}", engine, e))?;
+            configure(reverb.as_mut(), presets, options.wet_only)?;
+            Ok(reverb)
+        })
+        .collect::<Result<_, String>>()
+        .unwrap_or_else(|e| fail(&e));
+    let next = AtomicUsize::new(0);
+    let (next, files, output_paths, base) = (&next, &files, &output_paths, &base);
+    let mut results: Vec<(usize, Result<Report, String>)> = crossbeam::scope(|scope| {
+        let workers: Vec<_> = reverbs
+            .into_iter()
+            .map(|mut reverb| {
+                scope.spawn(move |_| {
+                    let mut results = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::SeqCst);
+                        let file = match files.get(index) {
+                            Some(file) => file,
+                            None => break,
+                        };
+                        let output_path = &output_paths[index];
+                        let result = output_path
+                            .parent()
+                            .map_or(Ok(()), fs::create_dir_all)
+                            .map_err(|e| format!("can't create {}: {}", output_path.display(), e))
+                            .and_then(|_| {
+                                process_file(
+                                    reverb.as_mut(),
+                                    options,
+                                    &base.join(file),
+                                    output_path,
+                                )
+                            });
+                        results.push((index, result));
+                    }
+                    results
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect()
+    })
+    .unwrap();
+    results.sort_by_key(|(index, _)| *index);
+
+    let mut failures = 0;
+    for (index, result) in results.iter() {
+        let file = files[*index].display();
+        match result {
+            Ok(report) => {
+                let mut notes = Vec::new();
+                if report.clipped != 0 {
+                    notes.push(format!("{} samples clipped", report.clipped));
+                }
+                if report.tail_cut {
+                    notes.push("tail cut before silence".to_string());
+                }
+                println!(
+                    "{:>7.1} dBFS  {}{}{}",
+                    20. * report.peak.max(1e-9).log10(),
+                    file,
+                    if notes.is_empty() { "" } else { "  warning: " },
+                    notes.join(", ")
+                );
+            }
+            Err(e) => {
+                failures += 1;
+                println!("  failed      {}: {}", file, e);
+            }
+        }
+    }
+    println!(
+        "{} files rendered, {} failed",
+        results.len() - failures,
+        failures
+    );
+    failures == 0
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    exit(1);
+}
+
+fn load(path: &Path) -> Sample {
+    if !path.is_file() {
+        fail(&format!("{} is not a file", path.display()));
+    }
+    Sample::load(path).unwrap_or_else(|e| fail(&format!("can't load {}: {}", path.display(), e)))
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            exit(2);
+        }
+    };
+
+    let preset = match options.preset {
+        Some(ref path) => Preset::load(path)
+            .unwrap_or_else(|e| fail(&format!("can't load {}: {}", path.display(), e))),
+        None => Preset::default(),
+    };
+    let engine = options
+        .engine
+        .as_deref()
+        .or(preset.engine.as_deref())
+        .unwrap_or("fdn")
+        .to_string();
+    let impulse_response = options.impulse_response.as_ref().map(|p| load(p));
+
+    let mut reverb: Box<dyn Reverb> = reverb::create(&engine, 44100., impulse_response.as_ref())
+        .unwrap_or_else(|e| fail(&format!("can't create engine `{}`: {:?}", engine, e)));
+
+    if options.list {
+        for p in reverb.parameters() {
+            println!(
+                "{}: {} [{}, {}] {}",
+                p.name, p.default, p.min, p.max, p.unit
+            );
+        }
+        return;
+    }
+
+    let overrides = Preset {
+        engine: None,
+        parameters: options.parameters.clone(),
+    };
+    let presets = [preset, overrides];
+    if let Err(e) = configure(reverb.as_mut(), &presets, options.wet_only) {
+        fail(&e);
+    }
+
+    if let Some(format) = options.raw {
+        match process_raw(reverb.as_mut(), &options, format) {
+            Ok(tail_cut) => {
+                if tail_cut {
+                    eprintln!("warning: the tail was still above the silence threshold");
+                }
+            }
+            // The reader went away, e.g. `| head`.
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+            Err(e) => fail(&e.to_string()),
+        }
+        return;
+    }
+
+    let input = options.input.to_string_lossy();
+    if options.input.is_dir() || input.contains(['*', '?']) {
+        if !process_batch(&options, &presets, &engine, impulse_response.as_ref()) {
+            exit(1);
+        }
+        return;
+    }
+
+    let output = options
+        .output
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("out.wav"));
+    match process_file(reverb.as_mut(), &options, &options.input, &output) {
+        Ok(report) => {
+            if report.clipped != 0 {
+                eprintln!("warning: {} samples clipped", report.clipped);
+            }
+            if report.tail_cut {
+                eprintln!("warning: the tail was still above the silence threshold");
+            }
+        }
+        Err(e) => fail(&e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fdn_reverb::reverb::ParameterInfo;
+
+    // Ignores its input, and outputs a ramp from 1 down to 0 in 64 frames.
+    struct Ramp {
+        level: f32,
+    }
+
+    impl Ramp {
+        fn new() -> Ramp {
+            Ramp { level: 1. }
+        }
+    }
+
+    impl Reverb for Ramp {
+        fn name(&self) -> &'static str {
+            "ramp"
+        }
+        fn process(&mut self, _input: &[f32], output: &mut [f32]) {
+            for o in output.chunks_mut(2) {
+                o[0] = self.level;
+                o[1] = -self.level;
+                self.level = (self.level - 1. / 64.).max(0.);
             }
         }
+        fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+            self.process(input, output);
+        }
+        fn reset(&mut self) {
+            self.level = 1.;
+        }
+        fn tail_size(&self) -> isize {
+            64
+        }
+        fn sample_rate(&self) -> f32 {
+            44100.
+        }
+        fn set_sample_rate(&mut self, _sample_rate: f32) {}
+        fn parameters(&self) -> &'static [ParameterInfo] {
+            &[]
+        }
+        fn set_parameter(&mut self, _index: usize, _value: f32) {}
+        fn parameter(&self, _index: usize) -> f32 {
+            0.
+        }
+    }
+
+    fn tail(tail: Tail) -> (Vec<f32>, bool) {
+        let mut output = Vec::new();
+        let cut = render_tail(&mut Ramp::new(), 1, tail, |f| output.extend_from_slice(f));
+        (output, cut)
+    }
+
+    #[test]
+    fn fixed_tail() {
+        let (output, cut) = tail(Tail::Fixed(40));
+        assert_eq!(output.len(), 80);
+        assert!(!cut);
+    }
+
+    #[test]
+    fn tail_stops_after_the_hold() {
+        // The ramp goes under 0.25 at frame 49, and has been under it for 10 frames at 58.
+        let (output, cut) = tail(Tail::UntilSilent {
+            threshold: 0.25,
+            hold: 10,
+            max: 1000,
+        });
+        assert_eq!(output.len(), 59 * 2);
+        assert!(!cut);
+    }
+
+    #[test]
+    fn tail_is_cut_at_the_max() {
+        let (output, cut) = tail(Tail::UntilSilent {
+            threshold: 0.25,
+            hold: 10,
+            max: 50,
+        });
+        assert_eq!(output.len(), 50 * 2);
+        assert!(cut);
+    }
+
+    #[test]
+    fn hold_of_one_stops_at_the_first_silent_frame() {
+        let (output, _) = tail(Tail::UntilSilent {
+            threshold: 0.25,
+            hold: 1,
+            max: 1000,
+        });
+        assert_eq!(output.len(), 50 * 2);
+        assert!(output[98] < 0.25);
+        assert!(output[96] >= 0.25);
+    }
+
+    #[test]
+    fn fade_out_ends_silent() {
+        let mut samples = vec![1.; 20];
+        fade_out(&mut samples, 2, 4);
+        assert_eq!(&samples[..12], &[1.; 12]);
+        assert!(samples[12] < 1. && samples[12] > samples[14]);
+        assert_eq!(samples[12], samples[13]);
+        assert!(samples[18].abs() < 1e-6 && samples[19].abs() < 1e-6);
+    }
+
+    #[test]
+    fn fade_out_longer_than_the_signal() {
+        let mut samples = vec![1.; 6];
+        fade_out(&mut samples, 2, 100);
+        assert!(samples[0] < 1.);
+        assert!(samples[4].abs() < 1e-6);
+        let mut samples = vec![1.; 6];
+        fade_out(&mut samples, 2, 0);
+        assert_eq!(samples, vec![1.; 6]);
+    }
+    fn wildcard_str(pattern: &str, name: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+        wildcard(&pattern, &name)
+    }
+
+    fn components(path: &str) -> Vec<String> {
+        path.split('/').map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_str("*.wav", "kick.wav"));
+        assert!(wildcard_str("*.wav", ".wav"));
+        assert!(!wildcard_str("*.wav", "kick.flac"));
+        assert!(wildcard_str("k?ck*", "kick_02.wav"));
+        assert!(!wildcard_str("k?ck", "kck"));
+        assert!(wildcard_str("*", ""));
+        assert!(!wildcard_str("?", ""));
+    }
+
+    #[test]
+    fn double_star_matches_any_depth() {
+        let pattern = components("**/*.wav");
+        assert!(matches(&pattern, &components("a.wav")));
+        assert!(matches(&pattern, &components("drums/kick/a.wav")));
+        assert!(!matches(&pattern, &components("drums/a.flac")));
+        let pattern = components("drums/**/a?.wav");
+        assert!(matches(&pattern, &components("drums/a1.wav")));
+        assert!(matches(&pattern, &components("drums/x/y/a2.wav")));
+        assert!(!matches(&pattern, &components("bass/a1.wav")));
+        // `*` doesn't cross directories.
+        assert!(!matches(&components("*.wav"), &components("drums/a.wav")));
+    }
+
+    #[test]
+    fn inputs_of_a_directory_and_a_pattern() {
+        let base = env::temp_dir().join("fdn-reverb-collect-inputs");
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("drums/kick")).unwrap();
+        for file in &[
+            "a.wav",
+            "b.txt",
+            "drums/c.WAV",
+            "drums/kick/d.flac",
+            "drums/kick/e.wav",
+        ] {
+            fs::write(base.join(file), b"").unwrap();
+        }
+
+        let (found_base, files) = collect_inputs(&base).unwrap();
+        assert_eq!(found_base, base);
+        let expected: Vec<PathBuf> = [
+            "a.wav",
+            "drums/c.WAV",
+            "drums/kick/d.flac",
+            "drums/kick/e.wav",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(files, expected);
+
+        let (found_base, files) = collect_inputs(&base.join("drums/**/?.wav")).unwrap();
+        assert_eq!(found_base, base.join("drums"));
+        assert_eq!(files, vec![PathBuf::from("kick/e.wav")]);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn output_paths_keep_colliding_extensions() {
+        let files: Vec<PathBuf> = ["a.wav", "a.flac", "b.ogg", "d/a.flac"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let expected: Vec<PathBuf> = [
+            "out/a.wav.wav",
+            "out/a.flac.wav",
+            "out/b.wav",
+            "out/d/a.wav",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(output_paths(Path::new("out"), &files), expected);
+    }
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn raw_rate_must_be_positive() {
+        for rate in &["0", "-44100", "0.5", "NaN"] {
+            assert!(
+                parse(&["--raw", "f32", "--rate", rate]).is_err(),
+                "{}",
+                rate
+            );
+        }
+        let options = parse(&["--raw", "f32", "--rate", "48000"]).unwrap();
+        assert_eq!(options.rate, Some(48000));
     }
-    dump_wav("out.wav", &output_pcm, s.channels(), s.rate()).unwrap();
 }
@@ -0,0 +1,47 @@
+use crate::delay_line::DelayLine;
+use crate::onepolelowpass::OnePoleLowPass;
+
+/// Feedback comb filter, with a lowpass in the feedback path.
+pub struct Comb {
+    feedback: f32,
+    delay: DelayLine,
+    damping: OnePoleLowPass,
+}
+
+impl Comb {
+    /// `max_delay` is in seconds, the delay is set to this value initially.
+    pub fn new(max_delay: f32, feedback: f32, damping: f32, sample_rate: f32) -> Comb {
+        let frames = (max_delay * sample_rate) as usize;
+        let mut delay = DelayLine::new(frames + 1);
+        delay.set_duration(frames);
+        Comb {
+            feedback,
+            delay,
+            damping: OnePoleLowPass::new(damping, sample_rate),
+        }
+    }
+
+    pub fn set_feedback(&mut self, feedback: f32) {
+        self.feedback = feedback;
+    }
+
+    pub fn set_damping(&mut self, damping: f32) {
+        self.damping.set_frequency(damping);
+    }
+
+    pub fn set_delay(&mut self, delay: f32) {
+        self.delay.set_duration(delay as usize);
+    }
+
+    pub fn reset(&mut self) {
+        self.delay.reset();
+        self.damping.reset();
+    }
+
+    pub fn process(&mut self, input: f32, output: &mut f32) {
+        let mut filtered = 0.0;
+        self.delay.read(output);
+        self.damping.process(*output, &mut filtered);
+        self.delay.write(input + self.feedback * filtered);
+    }
+}
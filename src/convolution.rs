@@ -0,0 +1,422 @@
+use crate::delay_line::DelayLine;
+use crate::fft::{Complex, Fft};
+use crate::resample::Resampler;
+use crate::reverb::{ParameterInfo, Reverb, DRY_WET, PRE_DELAY, WIDTH};
+use crate::utils::{stereo_output, Sample};
+
+const DEFAULT_BLOCK_SIZE: usize = 128;
+
+const PARAMETERS: &[ParameterInfo] = &[PRE_DELAY, WIDTH, DRY_WET];
+
+#[derive(Debug)]
+pub enum ConvolutionError {
+    /// Impulse responses must be mono, stereo, or true-stereo (LL, LR, RL, RR).
+    UnsupportedChannelCount(u32),
+}
+
+// (input channel, output channel, impulse response channel) for each convolution.
+fn routing(channels: usize) -> Result<&'static [(usize, usize, usize)], ConvolutionError> {
+    match channels {
+        1 => Ok(&[(0, 0, 0), (1, 1, 0)]),
+        2 => Ok(&[(0, 0, 0), (1, 1, 1)]),
+        4 => Ok(&[(0, 0, 0), (0, 1, 1), (1, 0, 2), (1, 1, 3)]),
+        n => Err(ConvolutionError::UnsupportedChannelCount(n as u32)),
+    }
+}
+
+/// Convert impulse response channels from the rate they were measured at to another rate.
+pub fn resample_channels(channels: &[Vec<f32>], from_rate: f32, to_rate: f32) -> Vec<Vec<f32>> {
+    if from_rate == to_rate {
+        return channels.to_vec();
+    }
+    let resampler = Resampler::new(from_rate.round() as u32, to_rate.round() as u32);
+    channels.iter().map(|c| resampler.process(c, 1)).collect()
+}
+
+// Spectra of the most recent input blocks of one input channel, for overlap-save.
+struct FrequencyDelayLine {
+    time: Vec<f32>,
+    spectra: Vec<Vec<Complex>>,
+    head: usize,
+}
+
+impl FrequencyDelayLine {
+    fn new(block_size: usize, partition_count: usize) -> FrequencyDelayLine {
+        FrequencyDelayLine {
+            time: vec![0.; 2 * block_size],
+            spectra: vec![vec![Complex::default(); 2 * block_size]; partition_count],
+            head: 0,
+        }
+    }
+    fn push(&mut self, block: &[f32], fft: &Fft) {
+        let block_size = block.len();
+        self.time.copy_within(block_size.., 0);
+        self.time[block_size..].copy_from_slice(block);
+        self.head = (self.head + 1) % self.spectra.len();
+        let spectrum = &mut self.spectra[self.head];
+        for (c, t) in spectrum.iter_mut().zip(self.time.iter()) {
+            *c = Complex::new(*t, 0.);
+        }
+        fft.forward(spectrum);
+    }
+    fn reset(&mut self) {
+        self.time.iter_mut().for_each(|t| *t = 0.);
+        for s in self.spectra.iter_mut() {
+            s.iter_mut().for_each(|c| *c = Complex::default());
+        }
+    }
+    // Spectrum of the input block `age` blocks ago.
+    fn spectrum(&self, age: usize) -> &[Complex] {
+        let count = self.spectra.len();
+        &self.spectra[(self.head + count - age) % count]
+    }
+}
+
+struct ConvolutionPath {
+    input: usize,
+    output: usize,
+    partitions: Vec<Vec<Complex>>,
+}
+
+/// Uniformly partitioned overlap-save convolution with a measured impulse response. The wet
+/// signal has a latency of one block.
+pub struct ConvolutionReverb {
+    drywet: f32,
+    width: f32,
+    pre_delays: [DelayLine; 2],
+    pre_delay: f32,
+    sample_rate: f32,
+    block_size: usize,
+    fft: Fft,
+    inputs: Vec<FrequencyDelayLine>,
+    paths: Vec<ConvolutionPath>,
+    input_blocks: [Vec<f32>; 2],
+    output_blocks: [Vec<f32>; 2],
+    position: usize,
+    accumulator: Vec<Complex>,
+    ir_frames: usize,
+    // The impulse response as loaded, and its rate: it is converted from this whenever the
+    // processing rate changes.
+    ir: Vec<Vec<f32>>,
+    ir_rate: f32,
+}
+
+impl ConvolutionReverb {
+    pub fn new(sample_rate: f32) -> ConvolutionReverb {
+        ConvolutionReverb::with_block_size(sample_rate, DEFAULT_BLOCK_SIZE)
+    }
+    pub fn with_block_size(sample_rate: f32, block_size: usize) -> ConvolutionReverb {
+        let max_pre_delay = (150. * sample_rate / 1000.) as usize;
+        let mut pre_delays = [DelayLine::new(max_pre_delay), DelayLine::new(max_pre_delay)];
+        for d in pre_delays.iter_mut() {
+            d.set_duration(0);
+        }
+        ConvolutionReverb {
+            drywet: 0.3,
+            width: 1.0,
+            pre_delays,
+            pre_delay: 0.,
+            sample_rate,
+            block_size,
+            fft: Fft::new(2 * block_size),
+            inputs: Vec::new(),
+            paths: Vec::new(),
+            input_blocks: [vec![0.; block_size], vec![0.; block_size]],
+            output_blocks: [vec![0.; block_size], vec![0.; block_size]],
+            position: 0,
+            accumulator: vec![Complex::default(); 2 * block_size],
+            ir_frames: 0,
+            ir: Vec::new(),
+            ir_rate: sample_rate,
+        }
+    }
+
+    /// Load a mono, stereo or true-stereo (LL, LR, RL, RR) impulse response.
+    pub fn set_impulse_response(&mut self, ir: &Sample) -> Result<(), ConvolutionError> {
+        let channels: Vec<Vec<f32>> = (0..ir.channels() as usize).map(|c| ir.channel(c)).collect();
+        self.set_impulse_response_at_rate(&channels, ir.rate() as f32)
+    }
+
+    /// Load impulse response channels at the processing rate.
+    pub fn set_impulse_response_channels(
+        &mut self,
+        channels: &[Vec<f32>],
+    ) -> Result<(), ConvolutionError> {
+        self.set_impulse_response_at_rate(channels, self.sample_rate)
+    }
+
+    /// Load impulse response channels measured at `rate`. They are resampled to the processing
+    /// rate, now and when it changes.
+    pub fn set_impulse_response_at_rate(
+        &mut self,
+        channels: &[Vec<f32>],
+        rate: f32,
+    ) -> Result<(), ConvolutionError> {
+        routing(channels.len())?;
+        self.ir = channels.to_vec();
+        self.ir_rate = rate;
+        self.load_impulse_response();
+        Ok(())
+    }
+
+    fn load_impulse_response(&mut self) {
+        if self.ir.is_empty() {
+            return;
+        }
+        let channels = resample_channels(&self.ir, self.ir_rate, self.sample_rate);
+        let routing = routing(channels.len()).unwrap();
+        self.ir_frames = channels.iter().map(|c| c.len()).max().unwrap_or(0);
+        let partition_count = std::cmp::max(1, self.ir_frames.div_ceil(self.block_size));
+
+        self.paths = routing
+            .iter()
+            .map(|&(input, output, channel)| ConvolutionPath {
+                input,
+                output,
+                partitions: self.partition(&channels[channel], partition_count),
+            })
+            .collect();
+        self.inputs = (0..2)
+            .map(|_| FrequencyDelayLine::new(self.block_size, partition_count))
+            .collect();
+    }
+
+    fn partition(&self, ir: &[f32], partition_count: usize) -> Vec<Vec<Complex>> {
+        (0..partition_count)
+            .map(|p| {
+                let mut spectrum = vec![Complex::default(); 2 * self.block_size];
+                let start = std::cmp::min(p * self.block_size, ir.len());
+                let end = std::cmp::min(start + self.block_size, ir.len());
+                for (c, v) in spectrum.iter_mut().zip(ir[start..end].iter()) {
+                    c.re = *v;
+                }
+                self.fft.forward(&mut spectrum);
+                spectrum
+            })
+            .collect()
+    }
+
+    pub fn set_pre_delay(&mut self, pre_delay: f32) {
+        let pre_delay_frames = (pre_delay * self.sample_rate / 1000.) as usize;
+        self.pre_delay = pre_delay;
+        for d in self.pre_delays.iter_mut() {
+            d.set_duration(pre_delay_frames);
+        }
+    }
+
+    pub fn set_width(&mut self, width: f32) {
+        self.width = width;
+    }
+
+    pub fn set_drywet(&mut self, drywet: f32) {
+        self.drywet = drywet;
+    }
+
+    fn process_block(&mut self) {
+        if self.paths.is_empty() {
+            return;
+        }
+        for (fdl, block) in self.inputs.iter_mut().zip(self.input_blocks.iter()) {
+            fdl.push(block, &self.fft);
+        }
+        for output in 0..2 {
+            self.accumulator
+                .iter_mut()
+                .for_each(|c| *c = Complex::default());
+            for path in self.paths.iter().filter(|p| p.output == output) {
+                let fdl = &self.inputs[path.input];
+                for (age, h) in path.partitions.iter().enumerate() {
+                    for ((acc, x), h) in self
+                        .accumulator
+                        .iter_mut()
+                        .zip(fdl.spectrum(age).iter())
+                        .zip(h.iter())
+                    {
+                        *acc += *x * *h;
+                    }
+                }
+            }
+            self.fft.inverse(&mut self.accumulator);
+            for (o, c) in self.output_blocks[output]
+                .iter_mut()
+                .zip(self.accumulator[self.block_size..].iter())
+            {
+                *o = c.re;
+            }
+        }
+    }
+
+    fn process_frame(&mut self, l: f32, r: f32) -> (f32, f32) {
+        self.input_blocks[0][self.position] = l;
+        self.input_blocks[1][self.position] = r;
+        let wet = (
+            self.output_blocks[0][self.position],
+            self.output_blocks[1][self.position],
+        );
+        self.position += 1;
+        if self.position == self.block_size {
+            self.process_block();
+            self.position = 0;
+        }
+        wet
+    }
+
+    /// Mono input, interleaved stereo output.
+    pub fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        for (i, o) in input.iter().zip(output.chunks_mut(2)) {
+            let mut predelayed = 0.0;
+            self.pre_delays[0].process(*i, &mut predelayed);
+            let (wet_l, wet_r) = self.process_frame(predelayed, predelayed);
+            let (l, r) = stereo_output(*i, *i, wet_l, wet_r, self.drywet, self.width);
+            o[0] = l;
+            o[1] = r;
+        }
+    }
+
+    /// Interleaved stereo input, interleaved stereo output.
+    pub fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        for (i, o) in input.chunks(2).zip(output.chunks_mut(2)) {
+            let mut predelayed = [0.0; 2];
+            self.pre_delays[0].process(i[0], &mut predelayed[0]);
+            self.pre_delays[1].process(i[1], &mut predelayed[1]);
+            let (wet_l, wet_r) = self.process_frame(predelayed[0], predelayed[1]);
+            let (l, r) = stereo_output(i[0], i[1], wet_l, wet_r, self.drywet, self.width);
+            o[0] = l;
+            o[1] = r;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pre_delays.iter_mut().for_each(|d| d.reset());
+        self.inputs.iter_mut().for_each(|i| i.reset());
+        for b in self
+            .input_blocks
+            .iter_mut()
+            .chain(self.output_blocks.iter_mut())
+        {
+            b.iter_mut().for_each(|s| *s = 0.);
+        }
+        self.position = 0;
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    pub fn latency(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn tail_size(&self) -> isize {
+        (self.ir_frames + self.block_size) as isize
+    }
+}
+
+impl Reverb for ConvolutionReverb {
+    fn name(&self) -> &'static str {
+        "convolution"
+    }
+    fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        ConvolutionReverb::process(self, input, output);
+    }
+    fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        ConvolutionReverb::process_stereo(self, input, output);
+    }
+    fn reset(&mut self) {
+        ConvolutionReverb::reset(self);
+    }
+    fn tail_size(&self) -> isize {
+        ConvolutionReverb::tail_size(self)
+    }
+    fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        let max_pre_delay = (150. * sample_rate / 1000.) as usize;
+        self.pre_delays = [DelayLine::new(max_pre_delay), DelayLine::new(max_pre_delay)];
+        self.sample_rate = sample_rate;
+        self.set_pre_delay(self.pre_delay);
+        self.load_impulse_response();
+        self.reset();
+    }
+    fn parameters(&self) -> &'static [ParameterInfo] {
+        PARAMETERS
+    }
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        match index {
+            0 => self.set_pre_delay(value),
+            1 => self.set_width(value),
+            2 => self.set_drywet(value),
+            _ => {}
+        }
+    }
+    fn parameter(&self, index: usize) -> f32 {
+        match index {
+            0 => self.pre_delay,
+            1 => self.width,
+            2 => self.drywet,
+            _ => 0.,
+        }
+    }
+}
+
+impl Default for ConvolutionReverb {
+    fn default() -> Self {
+        ConvolutionReverb::new(44100.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_direct_convolution() {
+        let ir: Vec<f32> = (0..300)
+            .map(|i| ((i * 7919) % 61) as f32 / 61. - 0.5)
+            .collect();
+        let input: Vec<f32> = (0..1000)
+            .map(|i| ((i * 104729) % 37) as f32 / 37. - 0.5)
+            .collect();
+        let mut reverb = ConvolutionReverb::with_block_size(44100., 64);
+        reverb.set_drywet(1.0);
+        reverb
+            .set_impulse_response_channels(std::slice::from_ref(&ir))
+            .unwrap();
+        let mut output = vec![0.; input.len() * 2];
+        reverb.process(&input, &mut output);
+
+        for n in 64..input.len() {
+            let expected: f32 = (0..ir.len())
+                .filter(|k| *k <= n - 64)
+                .map(|k| ir[k] * input[n - 64 - k])
+                .sum();
+            assert!((output[2 * n] - expected).abs() < 1e-3);
+            assert!((output[2 * n + 1] - expected).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn impulse_response_keeps_its_duration() {
+        let ir: Vec<f32> = (0..22050).map(|i| (-(i as f32) / 4000.).exp()).collect();
+        let ir_seconds = |reverb: &ConvolutionReverb| {
+            (reverb.tail_size() as usize - reverb.latency()) as f32 / reverb.sample_rate()
+        };
+
+        let mut reverb = ConvolutionReverb::new(44100.);
+        reverb
+            .set_impulse_response_channels(std::slice::from_ref(&ir))
+            .unwrap();
+        assert_eq!(ir_seconds(&reverb), 0.5);
+        reverb.set_sample_rate(88200.);
+        assert_eq!(ir_seconds(&reverb), 0.5);
+        reverb.set_sample_rate(48000.);
+        assert_eq!(ir_seconds(&reverb), 0.5);
+
+        let mut reverb = ConvolutionReverb::new(96000.);
+        reverb
+            .set_impulse_response_at_rate(std::slice::from_ref(&ir), 44100.)
+            .unwrap();
+        assert_eq!(ir_seconds(&reverb), 0.5);
+    }
+}
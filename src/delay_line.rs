@@ -1,3 +1,6 @@
+use crate::utils::clamp;
+use log::warn;
+
 pub struct DelayLine {
     memory: Vec<f32>,
     duration: usize,
@@ -20,14 +23,14 @@ impl DelayLine {
     }
     pub fn set_duration(&mut self, duration: usize) {
         let d = if duration > self.memory.len() {
-            println!("clipping duration in delay: {} >= {}", duration, self.memory.len());
+            warn!("clipping duration in delay: {} >= {}", duration, self.memory.len());
             self.memory.len()
         } else {
             duration
         };
         self.duration = d;
         self.write_index = self.write_index % self.memory.len();
-        self.read_index = if self.write_index > self.duration {
+        self.read_index = if self.write_index >= self.duration {
             self.write_index - self.duration
         } else {
             self.memory.len() - (self.duration - self.write_index)
@@ -36,6 +39,12 @@ impl DelayLine {
         // println!("rd {} wr {} len {} duration{}", self.read_index, self.write_index, self.memory.len(), self.duration);
         // panic!("Ok");
     }
+    pub fn duration(&self) -> usize {
+        self.duration
+    }
+    pub fn reset(&mut self) {
+        self.memory.iter_mut().for_each(|s| *s = 0.0);
+    }
     pub fn write(&mut self, input: f32) {
         self.memory[self.write_index] = input;
         self.write_index = (self.write_index + 1) % self.memory.len()
@@ -44,8 +53,52 @@ impl DelayLine {
         *output = self.memory[self.read_index];
         self.read_index = (self.read_index + 1) % self.memory.len();
     }
+    /// Read `delay` whole frames behind the last written frame. This doesn't move the read
+    /// index.
+    pub fn read_at(&self, delay: usize) -> f32 {
+        let len = self.memory.len();
+        let delay = std::cmp::min(delay, len - 1);
+        self.memory[(self.write_index + len - 1 - delay) % len]
+    }
+    /// Read `delay` frames behind the last written frame, interpolating linearly between
+    /// frames. This doesn't move the read index.
+    pub fn tap(&self, delay: f32) -> f32 {
+        let len = self.memory.len();
+        // A line of one frame only has the newest frame.
+        let delay = clamp(delay, 0., len.saturating_sub(2) as f32);
+        let whole = delay as usize;
+        let frac = delay - whole as f32;
+        let newest = self.write_index + len - 1;
+        let a = self.memory[(newest - whole) % len];
+        let b = self.memory[(newest + len - whole - 1) % len];
+        a + frac * (b - a)
+    }
     pub fn process(&mut self, input: f32, output: &mut f32) {
         self.write(input);
         self.read(output);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tap() {
+        let mut line = DelayLine::new(8);
+        for i in 0..10 {
+            line.write(i as f32);
+        }
+        assert_eq!(line.tap(0.), 9.);
+        assert_eq!(line.tap(2.5), 6.5);
+        assert_eq!(line.tap(100.), 3.);
+        assert_eq!(line.read_at(0), 9.);
+        assert_eq!(line.read_at(7), 2.);
+        assert_eq!(line.read_at(100), 2.);
+
+        let mut line = DelayLine::new(1);
+        line.write(1.);
+        assert_eq!(line.tap(0.), 1.);
+        assert_eq!(line.tap(5.), 1.);
+    }
+}
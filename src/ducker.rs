@@ -0,0 +1,159 @@
+use crate::envelope::time_constant;
+
+/// Downward compressor driven by the level of a key signal, to duck a signal under another:
+/// above the threshold, the gain goes down by `1 - 1 / ratio` dB per dB of key, down to
+/// `-depth` dB. The gain reduction comes in with the attack time and goes away with the release
+/// time.
+#[derive(Clone)]
+pub struct Ducker {
+    threshold: f32,
+    ratio: f32,
+    depth: f32,
+    attack: f32,
+    release: f32,
+    attack_time: f32,
+    release_time: f32,
+    // current gain reduction, in dB
+    reduction: f32,
+    sample_rate: f32,
+}
+
+impl Ducker {
+    pub fn new(sample_rate: f32) -> Ducker {
+        let mut ducker = Ducker {
+            threshold: -30.,
+            ratio: 4.,
+            depth: 24.,
+            attack: 0.,
+            release: 0.,
+            attack_time: 10.,
+            release_time: 300.,
+            reduction: 0.,
+            sample_rate,
+        };
+        ducker.set_sample_rate(sample_rate);
+        ducker
+    }
+
+    /// In dB.
+    pub fn set_threshold(&mut self, threshold: f32) {
+        self.threshold = threshold;
+    }
+
+    pub fn set_ratio(&mut self, ratio: f32) {
+        self.ratio = ratio.max(1.);
+    }
+
+    /// Maximum gain reduction, in dB.
+    pub fn set_depth(&mut self, depth: f32) {
+        self.depth = depth.max(0.);
+    }
+
+    /// In ms.
+    pub fn set_attack(&mut self, attack: f32) {
+        self.attack_time = attack;
+        self.attack = time_constant(attack, self.sample_rate);
+    }
+
+    /// In ms.
+    pub fn set_release(&mut self, release: f32) {
+        self.release_time = release;
+        self.release = time_constant(release, self.sample_rate);
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.set_attack(self.attack_time);
+        self.set_release(self.release_time);
+    }
+
+    pub fn threshold(&self) -> f32 {
+        self.threshold
+    }
+
+    pub fn ratio(&self) -> f32 {
+        self.ratio
+    }
+
+    pub fn depth(&self) -> f32 {
+        self.depth
+    }
+
+    pub fn attack_time(&self) -> f32 {
+        self.attack_time
+    }
+
+    pub fn release_time(&self) -> f32 {
+        self.release_time
+    }
+
+    pub fn reset(&mut self) {
+        self.reduction = 0.;
+    }
+
+    /// Returns the gain to apply for a key at `level`, linear.
+    pub fn process(&mut self, level: f32) -> f32 {
+        let level_db = 20. * level.max(1e-6).log10();
+        let over = level_db - self.threshold;
+        let target = if over > 0. {
+            (over * (1. - 1. / self.ratio)).min(self.depth)
+        } else {
+            0.
+        };
+        let coefficient = if target > self.reduction {
+            self.attack
+        } else {
+            self.release
+        };
+        self.reduction = target + coefficient * (self.reduction - target);
+        10.0f32.powf(-self.reduction / 20.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ducker() -> Ducker {
+        let mut ducker = Ducker::new(1000.);
+        ducker.set_threshold(-40.);
+        ducker.set_ratio(4.);
+        ducker.set_depth(60.);
+        ducker.set_attack(0.);
+        ducker.set_release(0.);
+        ducker
+    }
+
+    fn db(gain: f32) -> f32 {
+        20. * gain.log10()
+    }
+
+    #[test]
+    fn passes_under_the_threshold() {
+        let mut ducker = ducker();
+        assert_eq!(ducker.process(0.005), 1.);
+    }
+
+    #[test]
+    fn reduces_by_the_ratio() {
+        let mut ducker = ducker();
+        // 20 dB over the threshold
+        assert!((db(ducker.process(0.1)) + 15.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn reduces_down_to_the_depth() {
+        let mut ducker = ducker();
+        ducker.set_depth(12.);
+        assert!((db(ducker.process(1.)) + 12.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn releases_with_the_release_time() {
+        let mut ducker = ducker();
+        ducker.set_release(100.);
+        let ducked = ducker.process(1.);
+        let released = ducker.process(0.);
+        assert!(released > ducked && released < 1.);
+    }
+}
@@ -0,0 +1,105 @@
+/// Smoothing coefficient of a one-pole filter that gets about two thirds of the way to its
+/// target in `time` ms.
+pub fn time_constant(time: f32, sample_rate: f32) -> f32 {
+    if time <= 0. {
+        return 0.;
+    }
+    (-1000. / (time * sample_rate)).exp()
+}
+
+/// Peak envelope follower, with separate attack and release times.
+#[derive(Clone)]
+pub struct EnvelopeFollower {
+    attack: f32,
+    release: f32,
+    attack_time: f32,
+    release_time: f32,
+    envelope: f32,
+    sample_rate: f32,
+}
+
+impl EnvelopeFollower {
+    /// `attack` and `release` are in ms.
+    pub fn new(attack: f32, release: f32, sample_rate: f32) -> EnvelopeFollower {
+        EnvelopeFollower {
+            attack: time_constant(attack, sample_rate),
+            release: time_constant(release, sample_rate),
+            attack_time: attack,
+            release_time: release,
+            envelope: 0.,
+            sample_rate,
+        }
+    }
+
+    pub fn set_attack(&mut self, attack: f32) {
+        self.attack_time = attack;
+        self.attack = time_constant(attack, self.sample_rate);
+    }
+
+    pub fn set_release(&mut self, release: f32) {
+        self.release_time = release;
+        self.release = time_constant(release, self.sample_rate);
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.set_attack(self.attack_time);
+        self.set_release(self.release_time);
+    }
+
+    pub fn attack_time(&self) -> f32 {
+        self.attack_time
+    }
+
+    pub fn release_time(&self) -> f32 {
+        self.release_time
+    }
+
+    pub fn envelope(&self) -> f32 {
+        self.envelope
+    }
+
+    pub fn reset(&mut self) {
+        self.envelope = 0.;
+    }
+
+    /// Returns the level of the envelope, linear.
+    pub fn process(&mut self, input: f32) -> f32 {
+        let level = input.abs();
+        let coefficient = if level > self.envelope {
+            self.attack
+        } else {
+            self.release
+        };
+        self.envelope = level + coefficient * (self.envelope - level);
+        self.envelope
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_constant_covers_two_thirds_in_time() {
+        let coefficient = time_constant(10., 1000.);
+        let mut value = 0.;
+        for _ in 0..10 {
+            value = 1. + coefficient * (value - 1.);
+        }
+        assert!((value - (1. - (-1.0f32).exp())).abs() < 1e-4);
+        assert_eq!(time_constant(0., 1000.), 0.);
+    }
+
+    #[test]
+    fn follows_with_attack_and_release() {
+        let mut follower = EnvelopeFollower::new(0., 100., 1000.);
+        assert_eq!(follower.process(-0.5), 0.5);
+        let released = follower.process(0.);
+        assert!(released < 0.5 && released > 0.49);
+        follower.set_release(0.);
+        assert_eq!(follower.process(0.), 0.);
+        follower.reset();
+        assert_eq!(follower.envelope(), 0.);
+    }
+}
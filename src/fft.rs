@@ -0,0 +1,123 @@
+use std::f32::consts::PI;
+use std::ops::{Add, AddAssign, Mul, Sub};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex {
+    pub fn new(re: f32, im: f32) -> Complex {
+        Complex { re, im }
+    }
+    pub fn conj(self) -> Complex {
+        Complex::new(self.re, -self.im)
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, rhs: Complex) -> Complex {
+        Complex::new(self.re + rhs.re, self.im + rhs.im)
+    }
+}
+
+impl AddAssign for Complex {
+    fn add_assign(&mut self, rhs: Complex) {
+        self.re += rhs.re;
+        self.im += rhs.im;
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, rhs: Complex) -> Complex {
+        Complex::new(self.re - rhs.re, self.im - rhs.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, rhs: Complex) -> Complex {
+        Complex::new(
+            self.re * rhs.re - self.im * rhs.im,
+            self.re * rhs.im + self.im * rhs.re,
+        )
+    }
+}
+
+/// In-place iterative radix-2 FFT, for power-of-two sizes.
+pub struct Fft {
+    size: usize,
+    twiddles: Vec<Complex>,
+    bit_reversed: Vec<usize>,
+}
+
+impl Fft {
+    pub fn new(size: usize) -> Fft {
+        assert!(size.is_power_of_two(), "FFT size must be a power of two");
+        let twiddles = (0..size / 2)
+            .map(|i| {
+                let phase = -2. * PI * i as f32 / size as f32;
+                Complex::new(phase.cos(), phase.sin())
+            })
+            .collect();
+        let bits = size.trailing_zeros();
+        let bit_reversed = (0..size)
+            .map(|i| {
+                if bits == 0 {
+                    0
+                } else {
+                    i.reverse_bits() >> (usize::BITS - bits)
+                }
+            })
+            .collect();
+        Fft {
+            size,
+            twiddles,
+            bit_reversed,
+        }
+    }
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    pub fn forward(&self, buffer: &mut [Complex]) {
+        self.transform(buffer, false);
+    }
+    /// Inverse transform, scaled so that `inverse(forward(x)) == x`.
+    pub fn inverse(&self, buffer: &mut [Complex]) {
+        self.transform(buffer, true);
+        let scale = 1. / self.size as f32;
+        for c in buffer.iter_mut() {
+            c.re *= scale;
+            c.im *= scale;
+        }
+    }
+    fn transform(&self, buffer: &mut [Complex], inverse: bool) {
+        assert_eq!(buffer.len(), self.size);
+        for (i, j) in self.bit_reversed.iter().enumerate() {
+            if i < *j {
+                buffer.swap(i, *j);
+            }
+        }
+        let mut len = 2;
+        while len <= self.size {
+            let half = len / 2;
+            let stride = self.size / len;
+            for start in (0..self.size).step_by(len) {
+                for k in 0..half {
+                    let mut w = self.twiddles[k * stride];
+                    if inverse {
+                        w = w.conj();
+                    }
+                    let t = w * buffer[start + k + half];
+                    let u = buffer[start + k];
+                    buffer[start + k] = u + t;
+                    buffer[start + k + half] = u - t;
+                }
+            }
+            len *= 2;
+        }
+    }
+}
@@ -88,7 +88,7 @@ impl Filter {
         Filter::new(FilterType::Peaking, frequency, q, gain, sample_rate)
     }
     pub fn allpass(frequency: f32, q: f32, sample_rate: f32) -> Filter {
-        Filter::new(FilterType::Peaking, frequency, q, 1.0, sample_rate)
+        Filter::new(FilterType::AllPass, frequency, q, 1.0, sample_rate)
     }
     pub fn notch(frequency: f32, q: f32, sample_rate: f32) -> Filter {
         Filter::new(FilterType::Notch, frequency, q, 1.0, sample_rate)
@@ -105,6 +105,9 @@ impl Filter {
         self.gain = gain;
         self.set_params_on_biquad();
     }
+    pub fn reset(&mut self) {
+        self.biquad.reset();
+    }
     pub fn process(&mut self, input: f32, output: &mut f32) {
         self.biquad.process(input, output);
     }
@@ -0,0 +1,281 @@
+use crate::allpass::Allpass;
+use crate::comb::Comb;
+use crate::delay_line::DelayLine;
+use crate::reverb::{copy_parameters, ParameterInfo, Reverb, DRY_WET, PRE_DELAY, WIDTH};
+use crate::utils::{clamp, stereo_output};
+
+// Jezar's Freeverb tunings, in frames at 44100Hz.
+const REFERENCE_RATE: f32 = 44100.;
+const COMB_TUNINGS: [f32; 8] = [1116., 1188., 1277., 1356., 1422., 1491., 1557., 1617.];
+const ALLPASS_TUNINGS: [f32; 4] = [556., 441., 341., 225.];
+const MAX_SPREAD: f32 = 50.;
+const FIXED_GAIN: f32 = 0.015;
+const ALLPASS_FEEDBACK: f32 = 0.5;
+
+const PARAMETERS: &[ParameterInfo] = &[
+    PRE_DELAY,
+    ParameterInfo {
+        name: "room size",
+        default: 0.5,
+        min: 0.,
+        max: 1.,
+        unit: "",
+    },
+    ParameterInfo {
+        name: "damping",
+        default: 10000.,
+        min: 100.,
+        max: 20000.,
+        unit: "Hz",
+    },
+    ParameterInfo {
+        name: "spread",
+        default: 23.,
+        min: 0.,
+        max: MAX_SPREAD,
+        unit: "frames",
+    },
+    WIDTH,
+    DRY_WET,
+];
+
+// Eight parallel combs into four series allpasses.
+struct Channel {
+    combs: Vec<Comb>,
+    all_passes: Vec<Allpass>,
+}
+
+impl Channel {
+    fn new(spread: f32, feedback: f32, damping: f32, sample_rate: f32) -> Channel {
+        let mut channel = Channel {
+            combs: COMB_TUNINGS
+                .iter()
+                .map(|t| {
+                    Comb::new(
+                        (t + MAX_SPREAD) / REFERENCE_RATE,
+                        feedback,
+                        damping,
+                        sample_rate,
+                    )
+                })
+                .collect(),
+            all_passes: ALLPASS_TUNINGS
+                .iter()
+                .map(|t| Allpass::new((t + spread) / REFERENCE_RATE, ALLPASS_FEEDBACK, sample_rate))
+                .collect(),
+        };
+        channel.set_spread(spread, sample_rate);
+        channel
+    }
+    fn set_spread(&mut self, spread: f32, sample_rate: f32) {
+        let scale = sample_rate / REFERENCE_RATE;
+        for (c, t) in self.combs.iter_mut().zip(COMB_TUNINGS.iter()) {
+            c.set_delay((t + spread) * scale);
+        }
+        for (a, t) in self.all_passes.iter_mut().zip(ALLPASS_TUNINGS.iter()) {
+            a.set_delay((t + spread) * scale);
+        }
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        let mut sum = 0.0;
+        for c in self.combs.iter_mut() {
+            let mut o = 0.0;
+            c.process(input, &mut o);
+            sum += o;
+        }
+        for a in self.all_passes.iter_mut() {
+            let mut o = 0.0;
+            a.process(sum, &mut o);
+            sum = o;
+        }
+        sum
+    }
+    fn reset(&mut self) {
+        self.combs.iter_mut().for_each(|c| c.reset());
+        self.all_passes.iter_mut().for_each(|a| a.reset());
+    }
+}
+
+/// Schroeder-Moorer reverb with the Freeverb tunings. The right channel uses slightly longer
+/// delays than the left channel, by `spread` frames.
+pub struct Freeverb {
+    drywet: f32,
+    width: f32,
+    pre_delay: DelayLine,
+    pre_delay_time: f32,
+    channels: [Channel; 2],
+    room_size: f32,
+    damping: f32,
+    spread: f32,
+    sample_rate: f32,
+}
+
+impl Freeverb {
+    pub fn new(sample_rate: f32) -> Freeverb {
+        let mut pre_delay = DelayLine::new((150. * sample_rate / 1000.) as usize);
+        pre_delay.set_duration(0);
+        let room_size = 0.5;
+        let damping = 10000.;
+        let spread = 23.;
+        let feedback = Freeverb::feedback(room_size);
+        Freeverb {
+            drywet: 0.3,
+            width: 1.0,
+            pre_delay,
+            pre_delay_time: 0.,
+            channels: [
+                Channel::new(0., feedback, damping, sample_rate),
+                Channel::new(spread, feedback, damping, sample_rate),
+            ],
+            room_size,
+            damping,
+            spread,
+            sample_rate,
+        }
+    }
+
+    fn feedback(room_size: f32) -> f32 {
+        0.7 + 0.28 * room_size
+    }
+
+    pub fn set_pre_delay(&mut self, pre_delay: f32) {
+        let pre_delay_frames = (pre_delay * self.sample_rate / 1000.) as usize;
+        self.pre_delay_time = pre_delay;
+        self.pre_delay.set_duration(pre_delay_frames);
+    }
+
+    pub fn set_room_size(&mut self, room_size: f32) {
+        self.room_size = room_size;
+        let feedback = Freeverb::feedback(room_size);
+        for c in self.channels.iter_mut() {
+            c.combs
+                .iter_mut()
+                .for_each(|comb| comb.set_feedback(feedback));
+        }
+    }
+
+    pub fn set_damping(&mut self, damping: f32) {
+        self.damping = damping;
+        for c in self.channels.iter_mut() {
+            c.combs
+                .iter_mut()
+                .for_each(|comb| comb.set_damping(damping));
+        }
+    }
+
+    /// In frames at 44100Hz.
+    pub fn set_spread(&mut self, spread: f32) {
+        self.spread = clamp(spread, 0., MAX_SPREAD);
+        self.channels[1].set_spread(self.spread, self.sample_rate);
+    }
+
+    pub fn set_width(&mut self, width: f32) {
+        self.width = width;
+    }
+
+    pub fn set_drywet(&mut self, drywet: f32) {
+        self.drywet = drywet;
+    }
+
+    fn process_frame(&mut self, input: f32) -> (f32, f32) {
+        let mut predelayed = 0.0;
+        self.pre_delay.process(input, &mut predelayed);
+        let input = predelayed * FIXED_GAIN;
+        (
+            self.channels[0].process(input),
+            self.channels[1].process(input),
+        )
+    }
+
+    pub fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        for (i, o) in input.iter().zip(output.chunks_mut(2)) {
+            let (wet_l, wet_r) = self.process_frame(*i);
+            let (l, r) = stereo_output(*i, *i, wet_l, wet_r, self.drywet, self.width);
+            o[0] = l;
+            o[1] = r;
+        }
+    }
+
+    /// Both channels are fed with the sum of the input channels, as in the original.
+    pub fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        for (i, o) in input.chunks(2).zip(output.chunks_mut(2)) {
+            let (wet_l, wet_r) = self.process_frame(i[0] + i[1]);
+            let (l, r) = stereo_output(i[0], i[1], wet_l, wet_r, self.drywet, self.width);
+            o[0] = l;
+            o[1] = r;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pre_delay.reset();
+        self.channels.iter_mut().for_each(|c| c.reset());
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    pub fn tail_size(&self) -> isize {
+        // 60dB of decay through the longest comb.
+        let longest = COMB_TUNINGS[7] * self.sample_rate / REFERENCE_RATE;
+        let trips = -3. / Freeverb::feedback(self.room_size).log10();
+        (longest * trips) as isize
+    }
+}
+
+impl Reverb for Freeverb {
+    fn name(&self) -> &'static str {
+        "freeverb"
+    }
+    fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        Freeverb::process(self, input, output);
+    }
+    fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        Freeverb::process_stereo(self, input, output);
+    }
+    fn reset(&mut self) {
+        Freeverb::reset(self);
+    }
+    fn tail_size(&self) -> isize {
+        Freeverb::tail_size(self)
+    }
+    fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        let mut fresh = Freeverb::new(sample_rate);
+        copy_parameters(self, &mut fresh);
+        *self = fresh;
+    }
+    fn parameters(&self) -> &'static [ParameterInfo] {
+        PARAMETERS
+    }
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        match index {
+            0 => self.set_pre_delay(value),
+            1 => self.set_room_size(value),
+            2 => self.set_damping(value),
+            3 => self.set_spread(value),
+            4 => self.set_width(value),
+            5 => self.set_drywet(value),
+            _ => {}
+        }
+    }
+    fn parameter(&self, index: usize) -> f32 {
+        match index {
+            0 => self.pre_delay_time,
+            1 => self.room_size,
+            2 => self.damping,
+            3 => self.spread,
+            4 => self.width,
+            5 => self.drywet,
+            _ => 0.,
+        }
+    }
+}
+
+impl Default for Freeverb {
+    fn default() -> Self {
+        Freeverb::new(44100.)
+    }
+}
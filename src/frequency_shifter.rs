@@ -0,0 +1,128 @@
+use std::f32::consts::PI;
+
+// Olli Niemitalo's coefficients for a pair of allpass chains whose outputs are 90 degrees
+// apart, from about 20Hz to 20kHz at 44.1kHz.
+const PATH_A: [f32; 4] = [0.692_387_8, 0.936_065_4, 0.988_229_5, 0.998_748_8];
+const PATH_B: [f32; 4] = [0.402_192_1, 0.856_171_1, 0.972_290_9, 0.995_288_5];
+
+// Second-order allpass in z^-2: (a^2 - z^-2) / (1 - a^2 z^-2).
+#[derive(Default)]
+struct HilbertAllpass {
+    coefficient: f32,
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+impl HilbertAllpass {
+    fn new(a: f32) -> HilbertAllpass {
+        HilbertAllpass {
+            coefficient: a * a,
+            ..Default::default()
+        }
+    }
+    fn reset(&mut self) {
+        self.x = [0.; 2];
+        self.y = [0.; 2];
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        let output = self.coefficient * (input + self.y[1]) - self.x[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+}
+
+/// Hilbert transformer made of two allpass chains: it returns an in-phase and a quadrature
+/// version of its input, with the same magnitude.
+pub struct Hilbert {
+    path_a: [HilbertAllpass; 4],
+    path_b: [HilbertAllpass; 4],
+    // The first path is delayed by a frame.
+    delayed: f32,
+}
+
+impl Hilbert {
+    pub fn new() -> Hilbert {
+        Hilbert {
+            path_a: [
+                HilbertAllpass::new(PATH_A[0]),
+                HilbertAllpass::new(PATH_A[1]),
+                HilbertAllpass::new(PATH_A[2]),
+                HilbertAllpass::new(PATH_A[3]),
+            ],
+            path_b: [
+                HilbertAllpass::new(PATH_B[0]),
+                HilbertAllpass::new(PATH_B[1]),
+                HilbertAllpass::new(PATH_B[2]),
+                HilbertAllpass::new(PATH_B[3]),
+            ],
+            delayed: 0.,
+        }
+    }
+    pub fn reset(&mut self) {
+        self.path_a.iter_mut().for_each(|a| a.reset());
+        self.path_b.iter_mut().for_each(|a| a.reset());
+        self.delayed = 0.;
+    }
+    /// Returns the in-phase and quadrature signals.
+    pub fn process(&mut self, input: f32) -> (f32, f32) {
+        let a = self.path_a.iter_mut().fold(input, |x, ap| ap.process(x));
+        let b = self.path_b.iter_mut().fold(input, |x, ap| ap.process(x));
+        let i = self.delayed;
+        self.delayed = a;
+        (i, b)
+    }
+}
+
+impl Default for Hilbert {
+    fn default() -> Self {
+        Hilbert::new()
+    }
+}
+
+/// Single-sideband frequency shifter: every component of the input is moved by `shift` Hz, up
+/// or down. Unlike a pitch shift, this doesn't keep harmonic relationships, and a shift of a
+/// few Hz in a feedback loop smears its resonances. Can also be used on the wet output.
+pub struct FrequencyShifter {
+    hilbert: Hilbert,
+    shift: f32,
+    phase: f32,
+    sample_rate: f32,
+}
+
+impl FrequencyShifter {
+    pub fn new(sample_rate: f32) -> FrequencyShifter {
+        FrequencyShifter {
+            hilbert: Hilbert::new(),
+            shift: 0.,
+            phase: 0.,
+            sample_rate,
+        }
+    }
+
+    /// Shift in Hz, negative to shift down.
+    pub fn set_shift(&mut self, shift: f32) {
+        self.shift = shift;
+    }
+
+    pub fn shift(&self) -> f32 {
+        self.shift
+    }
+
+    /// Phase of the oscillator, in [0, 1), to decorrelate several shifters.
+    pub fn set_phase(&mut self, phase: f32) {
+        self.phase = phase.fract();
+    }
+
+    pub fn reset(&mut self) {
+        self.hilbert.reset();
+    }
+
+    pub fn process(&mut self, input: f32, output: &mut f32) {
+        let (i, q) = self.hilbert.process(input);
+        let angle = 2. * PI * self.phase;
+        *output = i * angle.cos() + q * angle.sin();
+        self.phase += self.shift / self.sample_rate;
+        self.phase -= self.phase.floor();
+    }
+}
@@ -0,0 +1,155 @@
+use crate::envelope::time_constant;
+
+/// Noise gate driven by the level of a key signal: it opens when the key goes above the
+/// threshold, stays open for the hold time after the key goes back under it, and then closes.
+/// The gain moves towards open or closed with the attack and release times.
+#[derive(Clone)]
+pub struct Gate {
+    threshold: f32,
+    hold: usize,
+    attack: f32,
+    release: f32,
+    threshold_db: f32,
+    hold_time: f32,
+    attack_time: f32,
+    release_time: f32,
+    gain: f32,
+    held: usize,
+    sample_rate: f32,
+}
+
+impl Gate {
+    pub fn new(sample_rate: f32) -> Gate {
+        let mut gate = Gate {
+            threshold: 0.,
+            hold: 0,
+            attack: 0.,
+            release: 0.,
+            threshold_db: -30.,
+            hold_time: 250.,
+            attack_time: 1.,
+            release_time: 5.,
+            gain: 0.,
+            held: 0,
+            sample_rate,
+        };
+        gate.set_sample_rate(sample_rate);
+        gate
+    }
+
+    /// In dB.
+    pub fn set_threshold(&mut self, threshold: f32) {
+        self.threshold_db = threshold;
+        self.threshold = 10.0f32.powf(threshold / 20.);
+    }
+
+    /// In ms.
+    pub fn set_hold(&mut self, hold: f32) {
+        self.hold_time = hold;
+        self.hold = (hold * self.sample_rate / 1000.) as usize;
+    }
+
+    /// In ms.
+    pub fn set_attack(&mut self, attack: f32) {
+        self.attack_time = attack;
+        self.attack = time_constant(attack, self.sample_rate);
+    }
+
+    /// In ms.
+    pub fn set_release(&mut self, release: f32) {
+        self.release_time = release;
+        self.release = time_constant(release, self.sample_rate);
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.set_threshold(self.threshold_db);
+        self.set_hold(self.hold_time);
+        self.set_attack(self.attack_time);
+        self.set_release(self.release_time);
+    }
+
+    pub fn threshold_db(&self) -> f32 {
+        self.threshold_db
+    }
+
+    pub fn hold_time(&self) -> f32 {
+        self.hold_time
+    }
+
+    pub fn attack_time(&self) -> f32 {
+        self.attack_time
+    }
+
+    pub fn release_time(&self) -> f32 {
+        self.release_time
+    }
+
+    pub fn reset(&mut self) {
+        self.gain = 0.;
+        self.held = 0;
+    }
+
+    /// Returns the gain to apply for a key at `level`, linear.
+    pub fn process(&mut self, level: f32) -> f32 {
+        let target = if level >= self.threshold {
+            self.held = self.hold;
+            1.
+        } else if self.held > 0 {
+            self.held -= 1;
+            1.
+        } else {
+            0.
+        };
+        let coefficient = if target > self.gain {
+            self.attack
+        } else {
+            self.release
+        };
+        self.gain = target + coefficient * (self.gain - target);
+        self.gain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gate() -> Gate {
+        let mut gate = Gate::new(1000.);
+        gate.set_threshold(-20.);
+        gate.set_hold(10.);
+        gate.set_attack(0.);
+        gate.set_release(0.);
+        gate
+    }
+
+    #[test]
+    fn opens_over_the_threshold() {
+        let mut gate = gate();
+        assert_eq!(gate.process(0.05), 0.);
+        assert_eq!(gate.process(0.2), 1.);
+    }
+
+    #[test]
+    fn holds_then_closes() {
+        let mut gate = gate();
+        gate.process(1.);
+        for _ in 0..10 {
+            assert_eq!(gate.process(0.), 1.);
+        }
+        assert_eq!(gate.process(0.), 0.);
+    }
+
+    #[test]
+    fn moves_with_attack_and_release() {
+        let mut gate = gate();
+        gate.set_attack(10.);
+        gate.set_release(10.);
+        gate.set_hold(0.);
+        let opening = gate.process(1.);
+        assert!(opening > 0. && opening < 1.);
+        let closing = gate.process(0.);
+        assert!(closing > 0. && closing < opening);
+    }
+}
@@ -0,0 +1,359 @@
+use crate::delay_line::DelayLine;
+use crate::onepolelowpass::OnePoleLowPass;
+use crate::random::Random;
+use crate::reverb::{copy_parameters, ParameterInfo, Reverb, DRY_WET, PRE_DELAY, WIDTH};
+use crate::softclip::Softclip;
+use crate::utils::{clamp, stereo_output};
+
+// Length of the capture buffer, in seconds.
+const CAPTURE: f32 = 4.;
+const MAX_GRAINS: usize = 64;
+const MIN_GRAIN_SIZE: f32 = 10.;
+const MAX_GRAIN_SIZE: f32 = 500.;
+const MIN_DENSITY: f32 = 1.;
+const MAX_DENSITY: f32 = 200.;
+const MAX_SCATTER: f32 = 2000.;
+const MAX_PITCH: f32 = 12.;
+
+const PARAMETERS: &[ParameterInfo] = &[
+    PRE_DELAY,
+    ParameterInfo {
+        name: "absorption",
+        default: 5000.,
+        min: 100.,
+        max: 20000.,
+        unit: "Hz",
+    },
+    ParameterInfo {
+        name: "grain size",
+        default: 80.,
+        min: MIN_GRAIN_SIZE,
+        max: MAX_GRAIN_SIZE,
+        unit: "ms",
+    },
+    ParameterInfo {
+        name: "density",
+        default: 40.,
+        min: MIN_DENSITY,
+        max: MAX_DENSITY,
+        unit: "grains/s",
+    },
+    ParameterInfo {
+        name: "scatter",
+        default: 500.,
+        min: 0.,
+        max: MAX_SCATTER,
+        unit: "ms",
+    },
+    ParameterInfo {
+        name: "pitch",
+        default: 0.1,
+        min: 0.,
+        max: MAX_PITCH,
+        unit: "semitones",
+    },
+    ParameterInfo {
+        name: "pan",
+        default: 0.8,
+        min: 0.,
+        max: 1.,
+        unit: "",
+    },
+    ParameterInfo {
+        name: "feedback",
+        default: 0.5,
+        min: 0.,
+        max: 0.95,
+        unit: "",
+    },
+    WIDTH,
+    DRY_WET,
+];
+
+#[derive(Clone, Copy, Default)]
+struct Grain {
+    active: bool,
+    // Delay behind the write position of the capture buffer when the grain starts, in frames.
+    start: f32,
+    length: f32,
+    age: f32,
+    // Playback speed.
+    rate: f32,
+    gains: (f32, f32),
+}
+
+/// Granular diffuser: short grains are read from a capture buffer of the input, at random
+/// positions in the recent past, with a random size, pitch and pan, and the grains are fed back
+/// into the buffer.
+pub struct GranularReverb {
+    drywet: f32,
+    width: f32,
+    pre_delay: DelayLine,
+    pre_delay_time: f32,
+    capture: DelayLine,
+    grains: [Grain; MAX_GRAINS],
+    random: Random,
+    // Frames until the next grain starts.
+    countdown: f32,
+    feedback_signal: f32,
+    lowpass: OnePoleLowPass,
+    softclip: Softclip,
+    absorbtion: f32,
+    grain_size: f32,
+    density: f32,
+    scatter: f32,
+    pitch: f32,
+    pan: f32,
+    feedback: f32,
+    sample_rate: f32,
+}
+
+impl GranularReverb {
+    pub fn new(sample_rate: f32) -> GranularReverb {
+        let mut pre_delay = DelayLine::new((150. * sample_rate / 1000.) as usize);
+        pre_delay.set_duration(0);
+        GranularReverb {
+            drywet: 0.3,
+            width: 1.0,
+            pre_delay,
+            pre_delay_time: 0.,
+            capture: DelayLine::new((CAPTURE * sample_rate) as usize),
+            grains: [Grain::default(); MAX_GRAINS],
+            random: Random::new(1),
+            countdown: 0.,
+            feedback_signal: 0.,
+            lowpass: OnePoleLowPass::new(5000., sample_rate),
+            softclip: Softclip::new(1.),
+            absorbtion: 5000.,
+            grain_size: 80.,
+            density: 40.,
+            scatter: 500.,
+            pitch: 0.1,
+            pan: 0.8,
+            feedback: 0.5,
+            sample_rate,
+        }
+    }
+
+    pub fn set_pre_delay(&mut self, pre_delay: f32) {
+        let pre_delay_frames = (pre_delay * self.sample_rate / 1000.) as usize;
+        self.pre_delay_time = pre_delay;
+        self.pre_delay.set_duration(pre_delay_frames);
+    }
+
+    pub fn set_absorbtion(&mut self, abs: f32) {
+        self.absorbtion = abs;
+        self.lowpass.set_frequency(abs);
+    }
+
+    /// Average length of the grains, in ms.
+    pub fn set_grain_size(&mut self, size: f32) {
+        self.grain_size = clamp(size, MIN_GRAIN_SIZE, MAX_GRAIN_SIZE);
+    }
+
+    /// Average number of grains started per second.
+    pub fn set_density(&mut self, density: f32) {
+        self.density = clamp(density, MIN_DENSITY, MAX_DENSITY);
+    }
+
+    /// How far back in the capture buffer the grains can start, in ms.
+    pub fn set_scatter(&mut self, scatter: f32) {
+        self.scatter = clamp(scatter, 0., MAX_SCATTER);
+    }
+
+    /// Maximum random detune of the grains, in semitones.
+    pub fn set_pitch(&mut self, pitch: f32) {
+        self.pitch = clamp(pitch, 0., MAX_PITCH);
+    }
+
+    /// How far from the center the grains can be panned.
+    pub fn set_pan(&mut self, pan: f32) {
+        self.pan = clamp(pan, 0., 1.);
+    }
+
+    pub fn set_feedback(&mut self, feedback: f32) {
+        self.feedback = clamp(feedback, 0., 0.95);
+    }
+
+    pub fn set_width(&mut self, width: f32) {
+        self.width = width;
+    }
+
+    pub fn set_drywet(&mut self, drywet: f32) {
+        self.drywet = drywet;
+    }
+
+    fn spawn(&mut self) {
+        let slot = match self.grains.iter_mut().find(|g| !g.active) {
+            Some(slot) => slot,
+            None => return,
+        };
+        let random = &mut self.random;
+        // between half and one and a half the grain size
+        let length = self.grain_size * (0.5 + random.next_f32()) * self.sample_rate / 1000.;
+        let rate = 2.0f32.powf(random.bipolar() * self.pitch / 12.);
+        // A grain that plays faster than the input must start far enough behind it not to
+        // overtake it, and a slower one must not run past the start of the buffer.
+        let earliest = (length * (rate - 1.)).max(0.) + 1.;
+        let latest = CAPTURE * self.sample_rate - 2. - (length * (1. - rate)).max(0.);
+        let start = clamp(
+            earliest + random.next_f32() * self.scatter * self.sample_rate / 1000.,
+            earliest,
+            latest,
+        );
+        let angle = (1. + random.bipolar() * self.pan) * std::f32::consts::FRAC_PI_4;
+        *slot = Grain {
+            active: true,
+            start,
+            length,
+            age: 0.,
+            rate,
+            gains: (angle.cos(), angle.sin()),
+        };
+    }
+
+    fn process_frame(&mut self, input: f32) -> (f32, f32) {
+        let mut predelayed = 0.0;
+        self.pre_delay.process(input, &mut predelayed);
+        self.capture.write(predelayed + self.feedback_signal);
+
+        self.countdown -= 1.;
+        if self.countdown <= 0. {
+            self.spawn();
+            let interval = self.sample_rate / self.density;
+            self.countdown += interval * (0.5 + self.random.next_f32());
+        }
+
+        let mut wet = (0., 0.);
+        for g in self.grains.iter_mut().filter(|g| g.active) {
+            // The capture buffer moves on by one frame per frame.
+            let delay = g.start + g.age * (1. - g.rate);
+            let envelope = (std::f32::consts::PI * g.age / g.length).sin();
+            let s = self.capture.tap(delay) * envelope * envelope;
+            wet.0 += s * g.gains.0;
+            wet.1 += s * g.gains.1;
+            g.age += 1.;
+            if g.age >= g.length {
+                g.active = false;
+            }
+        }
+        // The grains are uncorrelated: normalize by the square root of their average overlap.
+        let overlap = self.density * self.grain_size / 1000.;
+        let gain = 1. / overlap.max(1.).sqrt();
+        wet = (wet.0 * gain, wet.1 * gain);
+
+        let mut filtered = 0.0;
+        let mut clipped = 0.0;
+        self.lowpass.process((wet.0 + wet.1) / 2., &mut filtered);
+        self.softclip.process(filtered, &mut clipped);
+        self.feedback_signal = self.feedback * clipped;
+        wet
+    }
+
+    pub fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        for (i, o) in input.iter().zip(output.chunks_mut(2)) {
+            let (wet_l, wet_r) = self.process_frame(*i);
+            let (l, r) = stereo_output(*i, *i, wet_l, wet_r, self.drywet, self.width);
+            o[0] = l;
+            o[1] = r;
+        }
+    }
+
+    /// The capture buffer is mono: it is fed with the mid signal.
+    pub fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        for (i, o) in input.chunks(2).zip(output.chunks_mut(2)) {
+            let (wet_l, wet_r) = self.process_frame((i[0] + i[1]) / 2.);
+            let (l, r) = stereo_output(i[0], i[1], wet_l, wet_r, self.drywet, self.width);
+            o[0] = l;
+            o[1] = r;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pre_delay.reset();
+        self.capture.reset();
+        self.lowpass.reset();
+        self.grains.iter_mut().for_each(|g| g.active = false);
+        self.feedback_signal = 0.;
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    pub fn tail_size(&self) -> isize {
+        // A grain reads on average half the scatter in the past, and the longest grains are one
+        // and a half times the grain size.
+        let trip = (self.scatter / 2. + self.grain_size * 1.5) * self.sample_rate / 1000.;
+        let longest = (self.scatter + self.grain_size * 1.5) * self.sample_rate / 1000.;
+        if self.feedback <= 0. {
+            return longest as isize;
+        }
+        (longest + trip * -3. / self.feedback.log10()) as isize
+    }
+}
+
+impl Reverb for GranularReverb {
+    fn name(&self) -> &'static str {
+        "granular"
+    }
+    fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        GranularReverb::process(self, input, output);
+    }
+    fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        GranularReverb::process_stereo(self, input, output);
+    }
+    fn reset(&mut self) {
+        GranularReverb::reset(self);
+    }
+    fn tail_size(&self) -> isize {
+        GranularReverb::tail_size(self)
+    }
+    fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        let mut fresh = GranularReverb::new(sample_rate);
+        copy_parameters(self, &mut fresh);
+        *self = fresh;
+    }
+    fn parameters(&self) -> &'static [ParameterInfo] {
+        PARAMETERS
+    }
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        match index {
+            0 => self.set_pre_delay(value),
+            1 => self.set_absorbtion(value),
+            2 => self.set_grain_size(value),
+            3 => self.set_density(value),
+            4 => self.set_scatter(value),
+            5 => self.set_pitch(value),
+            6 => self.set_pan(value),
+            7 => self.set_feedback(value),
+            8 => self.set_width(value),
+            9 => self.set_drywet(value),
+            _ => {}
+        }
+    }
+    fn parameter(&self, index: usize) -> f32 {
+        match index {
+            0 => self.pre_delay_time,
+            1 => self.absorbtion,
+            2 => self.grain_size,
+            3 => self.density,
+            4 => self.scatter,
+            5 => self.pitch,
+            6 => self.pan,
+            7 => self.feedback,
+            8 => self.width,
+            9 => self.drywet,
+            _ => 0.,
+        }
+    }
+}
+
+impl Default for GranularReverb {
+    fn default() -> Self {
+        GranularReverb::new(44100.)
+    }
+}
@@ -0,0 +1,660 @@
+use crate::convolution::{resample_channels, ConvolutionError, ConvolutionReverb};
+use crate::delay_line::DelayLine;
+use crate::reverb::{ParameterInfo, Reverb, DRY_WET, PRE_DELAY, WIDTH};
+use crate::utils::{stereo_output, Sample};
+use crate::FDNReverb;
+use crossbeam::channel::{bounded, Receiver, Sender};
+use log::warn;
+use std::sync::Arc;
+use std::thread;
+
+// Amplitude of the impulse used to measure the level of the FDN tail, kept low so that the
+// softclip stays in its linear region.
+const PROBE_LEVEL: f32 = 0.01;
+// Duration of the window after the crossover over which levels are matched, in ms.
+const MATCH_WINDOW: f32 = 50.;
+
+const PARAMETERS: &[ParameterInfo] = &[
+    PRE_DELAY,
+    ParameterInfo {
+        name: "absorption",
+        default: 5000.,
+        min: 100.,
+        max: 20000.,
+        unit: "Hz",
+    },
+    ParameterInfo {
+        name: "size",
+        default: 20.,
+        min: 1.,
+        max: 1000.,
+        unit: "m",
+    },
+    ParameterInfo {
+        name: "crossover",
+        default: 80.,
+        min: 5.,
+        max: 150.,
+        unit: "ms",
+    },
+    WIDTH,
+    DRY_WET,
+];
+
+/// Estimate the RT60 of an impulse response from its Schroeder energy decay curve, using the
+/// -5dB to -25dB range (T20), or -5dB to -15dB (T10) for short or noisy responses.
+pub fn estimate_rt60(ir: &[f32], sample_rate: f32) -> Option<f32> {
+    let mut edc = vec![0.0f64; ir.len()];
+    let mut energy = 0.0f64;
+    for (e, s) in edc.iter_mut().zip(ir.iter()).rev() {
+        energy += (*s as f64) * (*s as f64);
+        *e = energy;
+    }
+    if energy == 0. {
+        return None;
+    }
+    let time_at = |db: f64| {
+        edc.iter()
+            .position(|e| 10. * (e / energy).log10() <= db)
+            .map(|i| i as f32 / sample_rate)
+    };
+    let start = time_at(-5.)?;
+    if let Some(end) = time_at(-25.) {
+        return Some(3. * (end - start));
+    }
+    time_at(-15.).map(|end| 6. * (end - start))
+}
+
+fn energy(channels: &[Vec<f32>], start: usize, end: usize) -> f32 {
+    channels
+        .iter()
+        .map(|c| {
+            let end = std::cmp::min(end, c.len());
+            let start = std::cmp::min(start, end);
+            c[start..end].iter().map(|s| s * s).sum::<f32>()
+        })
+        .sum::<f32>()
+        / channels.len() as f32
+}
+
+// What the early part and the late tail are fitted from.
+struct FitRequest {
+    generation: u64,
+    ir: Arc<Vec<Vec<f32>>>,
+    sample_rate: f32,
+    crossover: f32,
+    fade: f32,
+    size: f32,
+    absorbtion: f32,
+    // Of the late tail, used when the decay of the impulse response can't be estimated.
+    decay: f32,
+}
+
+// The early part, and the settings of the late tail, fitted to an impulse response.
+struct Fit {
+    generation: u64,
+    early: Box<ConvolutionReverb>,
+    rt60: Option<f32>,
+    late_pre_delay: f32,
+    late_gain: f32,
+}
+
+impl FitRequest {
+    fn frames(&self, ms: f32) -> usize {
+        (ms * self.sample_rate / 1000.) as usize
+    }
+
+    // Window the early part of the impulse response, and fit the late tail to the rest.
+    fn fit(&self) -> Result<Fit, ConvolutionError> {
+        let mut early = Box::new(ConvolutionReverb::new(self.sample_rate));
+        early.set_drywet(1.0);
+        if self.ir.is_empty() {
+            return Ok(Fit {
+                generation: self.generation,
+                early,
+                rt60: None,
+                late_pre_delay: 0.,
+                late_gain: 0.,
+            });
+        }
+        let crossover = self.frames(self.crossover);
+        let fade = std::cmp::max(1, self.frames(self.fade));
+        let fade_start = crossover.saturating_sub(fade);
+
+        let windowed: Vec<Vec<f32>> = self
+            .ir
+            .iter()
+            .map(|c| {
+                c.iter()
+                    .take(crossover)
+                    .enumerate()
+                    .map(|(i, s)| {
+                        if i < fade_start {
+                            *s
+                        } else {
+                            let x = (i - fade_start) as f32 / fade as f32;
+                            s * 0.5 * (1. + (std::f32::consts::PI * x).cos())
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        early.set_impulse_response_channels(&windowed)?;
+
+        let tail: Vec<f32> = self.ir[0].iter().skip(crossover).cloned().collect();
+        let rt60 = estimate_rt60(&tail, self.sample_rate);
+
+        // The late part starts fading in where the early part starts fading out, and has to
+        // be delayed by as much as the convolution.
+        let late_delay = fade_start + early.latency();
+        let late_pre_delay = (late_delay as f32 + 0.5) * 1000. / self.sample_rate;
+
+        let window_end = crossover + self.frames(MATCH_WINDOW);
+        let ir_energy = energy(&self.ir, crossover, window_end);
+        let late_energy = self.probe_late_energy(rt60, fade_start, crossover, window_end);
+        let late_gain = if late_energy > 0. {
+            (ir_energy / late_energy).sqrt()
+        } else {
+            0.
+        };
+        Ok(Fit {
+            generation: self.generation,
+            early,
+            rt60,
+            late_pre_delay,
+            late_gain,
+        })
+    }
+
+    // Energy of the response of a fresh FDN with the same settings, between `start` and `end`.
+    fn probe_late_energy(
+        &self,
+        rt60: Option<f32>,
+        fade_start: usize,
+        start: usize,
+        end: usize,
+    ) -> f32 {
+        let mut probe = FDNReverb::new(self.sample_rate);
+        probe.set_drywet(1.0);
+        probe.set_size(self.size);
+        probe.set_absorbtion(self.absorbtion);
+        match rt60 {
+            Some(rt60) => probe.set_rt60(rt60),
+            None => probe.set_decay(self.decay),
+        }
+        probe.set_pre_delay((fade_start as f32 + 0.5) * 1000. / self.sample_rate);
+
+        let mut input = vec![0.0; end];
+        input[0] = PROBE_LEVEL;
+        let mut output = vec![0.0; end * 2];
+        probe.process(&input, &mut output);
+        let channels = vec![
+            output.iter().step_by(2).cloned().collect::<Vec<f32>>(),
+            output
+                .iter()
+                .skip(1)
+                .step_by(2)
+                .cloned()
+                .collect::<Vec<f32>>(),
+        ];
+        energy(&channels, start, end) / (PROBE_LEVEL * PROBE_LEVEL)
+    }
+}
+
+enum Job {
+    Fit(FitRequest),
+    // An early part that was replaced, freed here rather than on the audio thread.
+    Free(Box<ConvolutionReverb>),
+}
+
+// A thread that fits the early part and the late tail, and frees the early parts they replace.
+// It stops when the reverb is dropped.
+struct Fitter {
+    jobs: Sender<Job>,
+    fitted: Receiver<Fit>,
+}
+
+impl Fitter {
+    fn spawn() -> Fitter {
+        let (jobs, pending) = bounded(4);
+        let (done, fitted) = bounded(1);
+        thread::spawn(move || {
+            for job in pending.iter() {
+                match job {
+                    Job::Fit(request) => match request.fit() {
+                        Ok(fit) => {
+                            if done.send(fit).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => warn!("can't fit the impulse response: {:?}", e),
+                    },
+                    Job::Free(early) => drop(early),
+                }
+            }
+        });
+        Fitter { jobs, fitted }
+    }
+
+    // Whether the request was queued.
+    fn request(&self, request: FitRequest) -> bool {
+        self.jobs.try_send(Job::Fit(request)).is_ok()
+    }
+
+    fn fitted(&self) -> Option<Fit> {
+        self.fitted.try_recv().ok()
+    }
+
+    fn free(&self, early: Box<ConvolutionReverb>) {
+        // When the queue is full, it is freed here.
+        let _ = self.jobs.try_send(Job::Free(early));
+    }
+}
+
+/// The first part of a measured impulse response is convolved exactly, and an `FDNReverb` with
+/// a matching decay and level takes over at the crossover point.
+pub struct HybridReverb {
+    drywet: f32,
+    width: f32,
+    // one per input channel
+    pre_delays: [DelayLine; 2],
+    pre_delay: f32,
+    sample_rate: f32,
+    early: Box<ConvolutionReverb>,
+    late: FDNReverb,
+    late_gain: f32,
+    size: f32,
+    absorbtion: f32,
+    crossover: f32,
+    fade: f32,
+    // The impulse response at the processing rate.
+    ir: Arc<Vec<Vec<f32>>>,
+    // The impulse response as loaded, and its rate, to convert it when the rate changes.
+    measured_ir: Vec<Vec<f32>>,
+    measured_rate: f32,
+    // Incremented by the parameter changes the early part and the late tail have to be fitted
+    // again for. The fits requested from the fitter, and applied, are numbered alike.
+    generation: u64,
+    requested: u64,
+    applied: u64,
+    fitter: Fitter,
+    predelayed: Vec<f32>,
+    early_wet: Vec<f32>,
+    late_wet: Vec<f32>,
+}
+
+impl HybridReverb {
+    pub fn new(sample_rate: f32) -> HybridReverb {
+        let max_pre_delay = (150. * sample_rate / 1000.) as usize;
+        let mut pre_delays = [DelayLine::new(max_pre_delay), DelayLine::new(max_pre_delay)];
+        for d in pre_delays.iter_mut() {
+            d.set_duration(0);
+        }
+        let mut early = ConvolutionReverb::new(sample_rate);
+        early.set_drywet(1.0);
+        let mut late = FDNReverb::new(sample_rate);
+        late.set_drywet(1.0);
+        let mut hybrid = HybridReverb {
+            drywet: 0.3,
+            width: 1.0,
+            pre_delays,
+            pre_delay: 0.,
+            sample_rate,
+            early: Box::new(early),
+            late,
+            late_gain: 0.0,
+            size: 20.,
+            absorbtion: 5000.,
+            crossover: 80.,
+            fade: 10.,
+            ir: Arc::new(Vec::new()),
+            measured_ir: Vec::new(),
+            measured_rate: sample_rate,
+            generation: 0,
+            requested: 0,
+            applied: 0,
+            fitter: Fitter::spawn(),
+            predelayed: Vec::new(),
+            early_wet: Vec::new(),
+            late_wet: Vec::new(),
+        };
+        hybrid.late.set_size(hybrid.size);
+        hybrid.late.set_absorbtion(hybrid.absorbtion);
+        hybrid
+    }
+
+    /// Load a mono, stereo or true-stereo impulse response. Only its first `crossover` ms are
+    /// convolved, the rest is used to fit the decay and level of the late tail.
+    pub fn set_impulse_response(&mut self, ir: &Sample) -> Result<(), ConvolutionError> {
+        let channels: Vec<Vec<f32>> = (0..ir.channels() as usize).map(|c| ir.channel(c)).collect();
+        self.set_impulse_response_at_rate(&channels, ir.rate() as f32)
+    }
+
+    /// Load impulse response channels at the processing rate.
+    pub fn set_impulse_response_channels(
+        &mut self,
+        channels: &[Vec<f32>],
+    ) -> Result<(), ConvolutionError> {
+        self.set_impulse_response_at_rate(channels, self.sample_rate)
+    }
+
+    /// Load impulse response channels measured at `rate`. They are resampled to the processing
+    /// rate, now and when it changes.
+    pub fn set_impulse_response_at_rate(
+        &mut self,
+        channels: &[Vec<f32>],
+        rate: f32,
+    ) -> Result<(), ConvolutionError> {
+        let resampled = Arc::new(resample_channels(channels, rate, self.sample_rate));
+        let previous = std::mem::replace(&mut self.ir, resampled);
+        self.generation += 1;
+        if let Err(e) = self.fit() {
+            self.ir = previous;
+            self.generation -= 1;
+            return Err(e);
+        }
+        self.measured_ir = channels.to_vec();
+        self.measured_rate = rate;
+        Ok(())
+    }
+
+    pub fn set_crossover(&mut self, crossover: f32) {
+        self.crossover = crossover;
+        self.fade = crossover / 8.;
+        self.generation += 1;
+    }
+
+    pub fn set_size(&mut self, size: f32) {
+        self.size = size;
+        self.late.set_size(size);
+        self.generation += 1;
+    }
+
+    pub fn set_absorbtion(&mut self, abs: f32) {
+        self.absorbtion = abs;
+        self.late.set_absorbtion(abs);
+        self.generation += 1;
+    }
+
+    pub fn set_pre_delay(&mut self, pre_delay: f32) {
+        let pre_delay_frames = (pre_delay * self.sample_rate / 1000.) as usize;
+        self.pre_delay = pre_delay;
+        for d in self.pre_delays.iter_mut() {
+            d.set_duration(pre_delay_frames);
+        }
+    }
+
+    pub fn set_width(&mut self, width: f32) {
+        self.width = width;
+    }
+
+    pub fn set_drywet(&mut self, drywet: f32) {
+        self.drywet = drywet;
+    }
+
+    /// Fit the early part and the late tail to the impulse response now, rather than in the
+    /// background, after the crossover, size or absorption changed.
+    pub fn settle(&mut self) {
+        if self.applied != self.generation {
+            if let Err(e) = self.fit() {
+                warn!("can't fit the impulse response: {:?}", e);
+            }
+        }
+    }
+
+    fn fit(&mut self) -> Result<(), ConvolutionError> {
+        let generation = self.generation;
+        let fit = self.fit_request().fit()?;
+        self.apply(fit);
+        self.requested = generation;
+        self.applied = generation;
+        Ok(())
+    }
+
+    fn fit_request(&self) -> FitRequest {
+        FitRequest {
+            generation: self.generation,
+            ir: self.ir.clone(),
+            sample_rate: self.sample_rate,
+            crossover: self.crossover,
+            fade: self.fade,
+            size: self.size,
+            absorbtion: self.absorbtion,
+            decay: self.late.decay(),
+        }
+    }
+
+    fn apply(&mut self, fit: Fit) {
+        let early = std::mem::replace(&mut self.early, fit.early);
+        self.fitter.free(early);
+        if let Some(rt60) = fit.rt60 {
+            self.late.set_rt60(rt60);
+        }
+        self.late.set_pre_delay(fit.late_pre_delay);
+        self.late_gain = fit.late_gain;
+    }
+
+    // Fitting allocates and renders a probe of the late tail: the parameter setters run on the
+    // audio thread, so it is done by the fitter, and the result swapped in when it is ready.
+    fn fit_in_background(&mut self) {
+        if self.requested != self.generation && self.fitter.request(self.fit_request()) {
+            self.requested = self.generation;
+        }
+        if let Some(fit) = self.fitter.fitted() {
+            if fit.generation > self.applied {
+                self.applied = fit.generation;
+                self.apply(fit);
+            } else {
+                self.fitter.free(fit.early);
+            }
+        }
+    }
+
+    fn frames(&self, ms: f32) -> usize {
+        (ms * self.sample_rate / 1000.) as usize
+    }
+
+    // Mix the dry input, `channels` channels interleaved, with the early and late wet signals.
+    fn mix(&self, input: &[f32], channels: usize, output: &mut [f32]) {
+        for (((i, o), e), l) in input
+            .chunks(channels)
+            .zip(output.chunks_mut(2))
+            .zip(self.early_wet.chunks(2))
+            .zip(self.late_wet.chunks(2))
+        {
+            let (left, right) = stereo_output(
+                i[0],
+                i[channels - 1],
+                e[0] + self.late_gain * l[0],
+                e[1] + self.late_gain * l[1],
+                self.drywet,
+                self.width,
+            );
+            o[0] = left;
+            o[1] = right;
+        }
+    }
+
+    pub fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        self.fit_in_background();
+        self.predelayed.resize(input.len(), 0.0);
+        self.early_wet.resize(input.len() * 2, 0.0);
+        self.late_wet.resize(input.len() * 2, 0.0);
+        for (i, p) in input.iter().zip(self.predelayed.iter_mut()) {
+            self.pre_delays[0].process(*i, p);
+        }
+        self.early.process(&self.predelayed, &mut self.early_wet);
+        self.late.process(&self.predelayed, &mut self.late_wet);
+        self.mix(input, 1, output);
+    }
+
+    pub fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        self.fit_in_background();
+        self.predelayed.resize(input.len(), 0.0);
+        self.early_wet.resize(input.len(), 0.0);
+        self.late_wet.resize(input.len(), 0.0);
+        for (i, p) in input.chunks(2).zip(self.predelayed.chunks_mut(2)) {
+            self.pre_delays[0].process(i[0], &mut p[0]);
+            self.pre_delays[1].process(i[1], &mut p[1]);
+        }
+        self.early
+            .process_stereo(&self.predelayed, &mut self.early_wet);
+        self.late
+            .process_stereo(&self.predelayed, &mut self.late_wet);
+        self.mix(input, 2, output);
+    }
+
+    pub fn reset(&mut self) {
+        self.pre_delays.iter_mut().for_each(|d| d.reset());
+        self.early.reset();
+        self.late.reset();
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    pub fn tail_size(&self) -> isize {
+        // From the settings rather than the convolution, that may not have been fitted yet.
+        let ir_frames = self.ir.iter().map(|c| c.len()).max().unwrap_or(0);
+        let early = std::cmp::min(self.frames(self.crossover), ir_frames) + self.early.latency();
+        std::cmp::max(early as isize, self.late.tail_size())
+    }
+}
+
+impl Reverb for HybridReverb {
+    fn name(&self) -> &'static str {
+        "hybrid"
+    }
+    fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        HybridReverb::process(self, input, output);
+    }
+    fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        HybridReverb::process_stereo(self, input, output);
+    }
+    fn reset(&mut self) {
+        HybridReverb::reset(self);
+    }
+    fn tail_size(&self) -> isize {
+        HybridReverb::tail_size(self)
+    }
+    fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        let mut fresh = HybridReverb::new(sample_rate);
+        fresh.drywet = self.drywet;
+        fresh.width = self.width;
+        fresh.crossover = self.crossover;
+        fresh.fade = self.fade;
+        fresh.set_pre_delay(self.pre_delay);
+        fresh.set_size(self.size);
+        fresh.set_absorbtion(self.absorbtion);
+        fresh.ir = Arc::new(resample_channels(
+            &self.measured_ir,
+            self.measured_rate,
+            sample_rate,
+        ));
+        fresh.measured_ir = std::mem::take(&mut self.measured_ir);
+        fresh.measured_rate = self.measured_rate;
+        fresh.generation += 1;
+        fresh.settle();
+        *self = fresh;
+    }
+    fn settle(&mut self) {
+        HybridReverb::settle(self);
+    }
+    fn parameters(&self) -> &'static [ParameterInfo] {
+        PARAMETERS
+    }
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        match index {
+            0 => self.set_pre_delay(value),
+            1 => self.set_absorbtion(value),
+            2 => self.set_size(value),
+            3 => self.set_crossover(value),
+            4 => self.set_width(value),
+            5 => self.set_drywet(value),
+            _ => {}
+        }
+    }
+    fn parameter(&self, index: usize) -> f32 {
+        match index {
+            0 => self.pre_delay,
+            1 => self.absorbtion,
+            2 => self.size,
+            3 => self.crossover,
+            4 => self.width,
+            5 => self.drywet,
+            _ => 0.,
+        }
+    }
+}
+
+impl Default for HybridReverb {
+    fn default() -> Self {
+        HybridReverb::new(44100.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decaying_ir() -> Vec<f32> {
+        (0..22050)
+            .map(|i| (-(i as f32) / 4000.).exp() * if i % 2 == 0 { 1. } else { -1. })
+            .collect()
+    }
+
+    fn reverb_with_ir() -> HybridReverb {
+        let mut reverb = HybridReverb::new(44100.);
+        reverb
+            .set_impulse_response_channels(&[decaying_ir()])
+            .unwrap();
+        reverb
+    }
+
+    #[test]
+    fn settle_fits_now() {
+        let mut reverb = reverb_with_ir();
+        let late_gain = reverb.late_gain;
+        reverb.set_crossover(20.);
+        assert_eq!(reverb.late_gain, late_gain);
+        reverb.settle();
+        assert_eq!(reverb.applied, reverb.generation);
+        assert!(reverb.late_gain != late_gain);
+    }
+
+    #[test]
+    fn fits_in_the_background() {
+        let mut settled = reverb_with_ir();
+        settled.set_crossover(20.);
+        settled.settle();
+
+        let mut reverb = reverb_with_ir();
+        reverb.set_crossover(20.);
+        let input = [0.0; 128];
+        let mut output = [0.0; 256];
+        let start = std::time::Instant::now();
+        while reverb.applied != reverb.generation {
+            assert!(start.elapsed().as_secs() < 10, "the fit never arrived");
+            reverb.process(&input, &mut output);
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(reverb.late_gain, settled.late_gain);
+        assert_eq!(reverb.late.decay(), settled.late.decay());
+    }
+
+    #[test]
+    fn impulse_response_keeps_its_duration() {
+        let ir = decaying_ir();
+        let mut reverb = HybridReverb::new(44100.);
+        reverb
+            .set_impulse_response_channels(std::slice::from_ref(&ir))
+            .unwrap();
+        assert_eq!(reverb.ir[0].len() as f32 / reverb.sample_rate(), 0.5);
+        reverb.set_sample_rate(88200.);
+        assert_eq!(reverb.ir[0].len() as f32 / reverb.sample_rate(), 0.5);
+    }
+}
@@ -1,22 +1,248 @@
 pub mod allpass;
 pub mod biquad;
+pub mod comb;
+pub mod convolution;
 pub mod delay_line;
+pub mod ducker;
+pub mod envelope;
+pub mod fft;
 pub mod filter;
+pub mod freeverb;
+pub mod frequency_shifter;
+pub mod gate;
+pub mod granular;
+pub mod hybrid;
+pub mod plate;
+pub mod random;
 pub mod softclip;
+pub mod spring;
 pub mod onepolelowpass;
+pub mod pitch_shift;
+pub mod preset;
+pub mod quantize;
+pub mod resample;
+pub mod reverb;
+pub mod sdn;
 pub mod utils;
+pub mod velvet;
+pub mod wav;
 
 use crate::allpass::Allpass;
 use crate::delay_line::DelayLine;
+use crate::ducker::Ducker;
+use crate::envelope::EnvelopeFollower;
 use crate::filter::Filter;
+use crate::frequency_shifter::FrequencyShifter;
+use crate::gate::Gate;
 use crate::onepolelowpass::OnePoleLowPass;
+use crate::pitch_shift::PitchShifter;
+use crate::reverb::{ParameterInfo, Reverb, DRY_WET, PRE_DELAY, WIDTH};
 use crate::softclip::Softclip;
 use crate::utils::{coprime_with_progression, hadamard, matrix_vector_multiply};
-use crate::utils::clamp;
+use log::debug;
+use crate::utils::{clamp, loop_gain_for_rt60, stereo_output};
+
+const PARAMETERS: &[ParameterInfo] = &[
+    PRE_DELAY,
+    ParameterInfo {
+        name: "absorption",
+        default: 2500.,
+        min: 100.,
+        max: 20000.,
+        unit: "Hz",
+    },
+    ParameterInfo {
+        name: "size",
+        default: 300.,
+        min: 1.,
+        max: 1000.,
+        unit: "m",
+    },
+    ParameterInfo {
+        name: "decay",
+        default: 0.8,
+        min: 0.,
+        max: 1.25,
+        unit: "",
+    },
+    ParameterInfo {
+        name: "hardness",
+        default: 1.25,
+        min: 1.,
+        max: 2.,
+        unit: "",
+    },
+    ParameterInfo {
+        name: "progression",
+        default: 1.16,
+        min: 1.,
+        max: 5.,
+        unit: "",
+    },
+    WIDTH,
+    DRY_WET,
+    ParameterInfo {
+        name: "shimmer mix",
+        default: 0.,
+        min: 0.,
+        max: 1.,
+        unit: "",
+    },
+    ParameterInfo {
+        name: "shimmer interval",
+        default: 12.,
+        min: -24.,
+        max: 24.,
+        unit: "semitones",
+    },
+    ParameterInfo {
+        name: "shimmer feedback",
+        default: 0.8,
+        min: 0.,
+        max: 1.,
+        unit: "",
+    },
+    ParameterInfo {
+        name: "frequency shift",
+        default: 0.,
+        min: -20.,
+        max: 20.,
+        unit: "Hz",
+    },
+    ParameterInfo {
+        name: "gate",
+        default: 0.,
+        min: 0.,
+        max: 1.,
+        unit: "",
+    },
+    ParameterInfo {
+        name: "gate threshold",
+        default: -30.,
+        min: -80.,
+        max: 0.,
+        unit: "dB",
+    },
+    ParameterInfo {
+        name: "gate hold",
+        default: 250.,
+        min: 0.,
+        max: 1000.,
+        unit: "ms",
+    },
+    ParameterInfo {
+        name: "gate attack",
+        default: 1.,
+        min: 0.,
+        max: 100.,
+        unit: "ms",
+    },
+    ParameterInfo {
+        name: "gate release",
+        default: 5.,
+        min: 0.,
+        max: 1000.,
+        unit: "ms",
+    },
+    ParameterInfo {
+        name: "ducking",
+        default: 0.,
+        min: 0.,
+        max: 1.,
+        unit: "",
+    },
+    ParameterInfo {
+        name: "ducking threshold",
+        default: -30.,
+        min: -60.,
+        max: 0.,
+        unit: "dB",
+    },
+    ParameterInfo {
+        name: "ducking ratio",
+        default: 4.,
+        min: 1.,
+        max: 20.,
+        unit: "",
+    },
+    ParameterInfo {
+        name: "ducking depth",
+        default: 24.,
+        min: 0.,
+        max: 60.,
+        unit: "dB",
+    },
+    ParameterInfo {
+        name: "ducking attack",
+        default: 10.,
+        min: 0.1,
+        max: 100.,
+        unit: "ms",
+    },
+    ParameterInfo {
+        name: "ducking release",
+        default: 300.,
+        min: 10.,
+        max: 2000.,
+        unit: "ms",
+    },
+    ParameterInfo {
+        name: "dynamic decay",
+        default: 0.,
+        min: 0.,
+        max: 1.,
+        unit: "",
+    },
+    ParameterInfo {
+        name: "idle rt60",
+        default: 4.,
+        min: 0.1,
+        max: 20.,
+        unit: "s",
+    },
+    ParameterInfo {
+        name: "active rt60",
+        default: 1.,
+        min: 0.1,
+        max: 20.,
+        unit: "s",
+    },
+    ParameterInfo {
+        name: "decay attack",
+        default: 50.,
+        min: 1.,
+        max: 1000.,
+        unit: "ms",
+    },
+    ParameterInfo {
+        name: "decay release",
+        default: 500.,
+        min: 10.,
+        max: 5000.,
+        unit: "ms",
+    },
+];
+
+// Key levels, in dBFS, under which the decay is the idle decay, and over which it is the active
+// decay.
+const IDLE_LEVEL: f32 = -60.;
+const ACTIVE_LEVEL: f32 = -20.;
+// Number of frames between two updates of the dynamic decay.
+const DECAY_INTERVAL: usize = 32;
+
+// Window of the shimmer pitch shifters, in seconds.
+const SHIMMER_WINDOW: f32 = 0.05;
+
+// Attack and release of the peak follower on the key that drives the gate and the ducker, in
+// ms: fast enough to follow transients, slow enough not to follow the waveform of the bass.
+const KEY_ATTACK: f32 = 0.1;
+const KEY_RELEASE: f32 = 10.;
 
 pub struct FDNReverb {
     drywet: f32,
-    pre_delay: DelayLine,
+    // one per input channel
+    pre_delays: [DelayLine; 2],
+    pre_delay: f32,
     // four all pass
     all_passes: [Allpass; 4],
     // four delay lines
@@ -26,6 +252,30 @@ pub struct FDNReverb {
     feedback_amount: f32,
     softclip: Softclip,
     lowpasses: [OnePoleLowPass; 4],
+    absorbtion: f32,
+    // one pitch shifter per delay line, for the shimmer
+    shifters: [PitchShifter; 4],
+    shimmer_mix: f32,
+    shimmer_interval: f32,
+    shimmer_feedback: f32,
+    // one frequency shifter per delay line
+    frequency_shifters: [FrequencyShifter; 4],
+    // dynamics on the wet signal, keyed from the input or a sidechain
+    key_follower: EnvelopeFollower,
+    gate: Gate,
+    gate_enabled: bool,
+    ducker: Ducker,
+    ducking_enabled: bool,
+    // decay driven by the level of the key
+    dynamic_decay: bool,
+    decay_follower: EnvelopeFollower,
+    idle_rt60: f32,
+    active_rt60: f32,
+    dynamic_feedback: f32,
+    // frames until the next update of the dynamic decay
+    decay_countdown: usize,
+    // average length of a trip around the loop, in frames
+    loop_frames: f32,
     sample_rate: f32,
     size: f32,
     progression: f32,
@@ -42,11 +292,14 @@ impl FDNReverb {
         let delay_times = coprime_with_progression(delay_time as u64, progression, 4);
         let allpass_times = coprime_with_progression(allpass_time, progression, 4);
 
-        println!("{:?}", delay_times.iter().map(|t| *t as f32 / sample_rate * 1000.).collect::<Vec::<f32>>());
-        println!("{:?}", allpass_times.iter().map(|t| *t as f32 / sample_rate * 1000.).collect::<Vec::<f32>>());
+        debug!("{:?}", delay_times.iter().map(|t| *t as f32 / sample_rate * 1000.).collect::<Vec::<f32>>());
+        debug!("{:?}", allpass_times.iter().map(|t| *t as f32 / sample_rate * 1000.).collect::<Vec::<f32>>());
 
-        let mut pre_delay = DelayLine::new((150. * sample_rate / 1000.) as usize);
-        pre_delay.set_duration(0);
+        let max_pre_delay = (150. * sample_rate / 1000.) as usize;
+        let mut pre_delays = [DelayLine::new(max_pre_delay), DelayLine::new(max_pre_delay)];
+        for d in pre_delays.iter_mut() {
+            d.set_duration(0);
+        }
 
         let all_passes = [
             Allpass::new(allpass_times[0] as f32 / sample_rate, 0.6, sample_rate),
@@ -87,8 +340,30 @@ impl FDNReverb {
             OnePoleLowPass::new(2500., sample_rate)
         ];
 
-        return FDNReverb {
-            pre_delay,
+        let mut shifters = [
+            PitchShifter::new(SHIMMER_WINDOW, sample_rate),
+            PitchShifter::new(SHIMMER_WINDOW, sample_rate),
+            PitchShifter::new(SHIMMER_WINDOW, sample_rate),
+            PitchShifter::new(SHIMMER_WINDOW, sample_rate),
+        ];
+        for (i, s) in shifters.iter_mut().enumerate() {
+            s.set_interval(12.);
+            // don't sweep all the shifters together
+            s.set_phase(i as f32 / 4.);
+        }
+        let mut frequency_shifters = [
+            FrequencyShifter::new(sample_rate),
+            FrequencyShifter::new(sample_rate),
+            FrequencyShifter::new(sample_rate),
+            FrequencyShifter::new(sample_rate),
+        ];
+        for (i, s) in frequency_shifters.iter_mut().enumerate() {
+            s.set_phase(i as f32 / 4.);
+        }
+
+        let mut reverb = FDNReverb {
+            pre_delays,
+            pre_delay: 0.,
             drywet: 0.3,
             all_passes,
             delays,
@@ -96,16 +371,36 @@ impl FDNReverb {
             feedback,
             softclip: Softclip::new(1.25),
             lowpasses,
+            absorbtion: 2500.,
+            shifters,
+            shimmer_mix: 0.,
+            shimmer_interval: 12.,
+            shimmer_feedback: 0.8,
+            frequency_shifters,
+            key_follower: EnvelopeFollower::new(KEY_ATTACK, KEY_RELEASE, sample_rate),
+            gate: Gate::new(sample_rate),
+            gate_enabled: false,
+            ducker: Ducker::new(sample_rate),
+            ducking_enabled: false,
+            dynamic_decay: false,
+            decay_follower: EnvelopeFollower::new(50., 500., sample_rate),
+            idle_rt60: 4.,
+            active_rt60: 1.,
+            dynamic_feedback: 0.,
+            decay_countdown: 0,
+            loop_frames: 0.,
             feedback_amount: 0.8,
             sample_rate,
             size,
             progression,
             width: 1.0
         };
+        reverb.update_loop_frames();
+        reverb
     }
     // [0, 1000]
     pub fn set_size(&mut self, size: f32) {
-        println!("room size {}", size);
+        debug!("room size {}", size);
         self.size = size;
         // size in meter
         let s = if size < 1. { 1. } else { size };
@@ -114,8 +409,8 @@ impl FDNReverb {
         let duration_to_wall_frames_2 = (duration_to_wall_s / 35. * self.sample_rate) as u64;
         let progression = coprime_with_progression(duration_to_wall_frames, self.progression, 4);
         let progression_2 = coprime_with_progression(duration_to_wall_frames_2, self.progression, 4);
-        println!("delays {:?}", progression.iter().map(|t| *t as f32 / self.sample_rate * 1000.).collect::<Vec::<f32>>());
-        println!("allpasses {:?}", progression_2.iter().map(|t| *t as f32 / self.sample_rate * 1000.).collect::<Vec::<f32>>());
+        debug!("delays {:?}", progression.iter().map(|t| *t as f32 / self.sample_rate * 1000.).collect::<Vec::<f32>>());
+        debug!("allpasses {:?}", progression_2.iter().map(|t| *t as f32 / self.sample_rate * 1000.).collect::<Vec::<f32>>());
         // all passes are kept below 30ms
         for (ap, v) in self.all_passes.iter_mut().zip(progression_2.iter()) {
             ap.set_delay((*v) as f32);
@@ -123,24 +418,92 @@ impl FDNReverb {
         for (d, v) in self.delays.iter_mut().zip(progression.iter()) {
             d.set_duration((*v) as usize);
         }
+        self.update_loop_frames();
     }
 
-    // [0, 1.25]
     pub fn set_pre_delay(&mut self, pre_delay: f32) {
         let pre_delay_frames = (pre_delay * self.sample_rate / 1000.) as usize;
-        println!("pre-delay: {}", pre_delay);
-        self.pre_delay.set_duration(pre_delay_frames);
+        debug!("pre-delay: {}", pre_delay);
+        self.pre_delay = pre_delay;
+        for d in self.pre_delays.iter_mut() {
+            d.set_duration(pre_delay_frames);
+        }
     }
     // [0, 1.25]
     pub fn set_decay(&mut self, decay: f32) {
-        println!("feedback: {}", decay);
+        debug!("feedback: {}", decay);
         self.feedback_amount = decay;
         for a in self.all_passes.iter_mut() {
             a.set_gain(clamp(decay, 0.0, 0.6));
         }
     }
+    pub fn decay(&self) -> f32 {
+        self.feedback_amount
+    }
+    /// Set the decay so that the tail of a quiet signal lasts `rt60` seconds. The gain of the
+    /// unnormalized Hadamard matrix (2) and the small-signal gain of the softclip are factored
+    /// out of the feedback amount.
+    pub fn set_rt60(&mut self, rt60: f32) {
+        let loop_gain = loop_gain_for_rt60(rt60, self.loop_frames, self.sample_rate);
+        self.set_decay(loop_gain / (2. * self.softclip.hardness()));
+    }
+    // Measure the average length of a trip around the loop, after the delays change.
+    fn update_loop_frames(&mut self) {
+        self.loop_frames = self
+            .delays
+            .iter()
+            .zip(self.all_passes.iter())
+            .map(|(d, a)| (d.duration() + a.delay()) as f32)
+            .sum::<f32>()
+            / 4.;
+    }
+    /// Drive the decay with the level of the key instead of `set_decay`: the tail lasts the
+    /// active RT60 while the key is loud, and the idle RT60 when it is quiet.
+    pub fn set_dynamic_decay(&mut self, enabled: bool) {
+        if enabled && !self.dynamic_decay {
+            self.decay_follower.reset();
+            self.decay_countdown = 0;
+        }
+        self.dynamic_decay = enabled;
+    }
+    /// In seconds.
+    pub fn set_idle_rt60(&mut self, rt60: f32) {
+        self.idle_rt60 = rt60;
+    }
+    /// In seconds.
+    pub fn set_active_rt60(&mut self, rt60: f32) {
+        self.active_rt60 = rt60;
+    }
+    /// How fast the decay follows the key, in ms.
+    pub fn set_decay_attack(&mut self, attack: f32) {
+        self.decay_follower.set_attack(attack);
+    }
+    /// In ms.
+    pub fn set_decay_release(&mut self, release: f32) {
+        self.decay_follower.set_release(release);
+    }
+    // Follow the key, and compute the feedback amount from its level every `DECAY_INTERVAL`
+    // frames. The envelope follower smooths the changes, so that they don't click.
+    fn modulate_decay(&mut self, key: f32) {
+        if !self.dynamic_decay {
+            return;
+        }
+        let envelope = self.decay_follower.process(key);
+        if self.decay_countdown > 0 {
+            self.decay_countdown -= 1;
+            return;
+        }
+        self.decay_countdown = DECAY_INTERVAL - 1;
+        let level = 20. * envelope.max(1e-6).log10();
+        let activity = clamp((level - IDLE_LEVEL) / (ACTIVE_LEVEL - IDLE_LEVEL), 0., 1.);
+        // interpolate the decay rates, so that the RT60 moves smoothly between the two
+        let rate = (1. - activity) / self.idle_rt60.max(0.01) + activity / self.active_rt60.max(0.01);
+        let loop_gain = 10.0f32.powf(-3. * self.loop_frames * rate / self.sample_rate);
+        self.dynamic_feedback = loop_gain / (2. * self.softclip.hardness());
+    }
     // [0, 20000]
     pub fn set_absorbtion(&mut self, abs: f32) {
+        self.absorbtion = abs;
         for f in self.lowpasses.iter_mut() {
             f.set_frequency(abs);
         }
@@ -161,63 +524,343 @@ impl FDNReverb {
     }
 
     pub fn set_drywet(&mut self, drywet: f32) {
-        println!("drywet: {}", drywet);
+        debug!("drywet: {}", drywet);
         self.drywet = drywet;
     }
-    pub fn process(&mut self, input: &[f32], output: &mut [f32]) {
-        let mut idx = 0;
-        for ii in 0..input.len() {
-            let mut a = [0.; 4];
-            let mut b = [0.; 4];
-            let mut predelayed = 0.0;
 
-            self.pre_delay.process(input[ii], &mut predelayed);
+    /// Proportion of the loop that goes through the shimmer pitch shifters. 0 disables them.
+    pub fn set_shimmer_mix(&mut self, mix: f32) {
+        if self.shimmer_mix == 0. && mix > 0. {
+            // they haven't been running, clear their history
+            self.shifters.iter_mut().for_each(|s| s.reset());
+        }
+        self.shimmer_mix = clamp(mix, 0., 1.);
+    }
 
-            for i in 0..4 {
-                self.lowpasses[i].process(predelayed + self.feedback[i], &mut a[i]);
-            }
-            for i in 0..4 {
-                self.all_passes[i].process(a[i], &mut b[i]);
-            }
-            for i in 0..4 {
-                self.delays[i].process(b[i], &mut a[i]);
-            }
-            for i in 0..4 {
-                self.softclip.process(a[i], &mut b[i]);
-            }
+    /// Interval of the shimmer, in semitones.
+    pub fn set_shimmer_interval(&mut self, semitones: f32) {
+        self.shimmer_interval = semitones;
+        for s in self.shifters.iter_mut() {
+            s.set_interval(semitones);
+        }
+    }
 
-            a = matrix_vector_multiply(&b, &self.feedback_matrix.into());
+    /// Gain of the pitch shifted signal in the loop. Kept at or below one so that the loop stays
+    /// stable: the shifters never amplify.
+    pub fn set_shimmer_feedback(&mut self, feedback: f32) {
+        self.shimmer_feedback = clamp(feedback, 0., 1.);
+    }
 
-            for i in 0..4 {
-                self.feedback[i] = a[i] * self.feedback_amount;
+    /// Shift of the frequency shifters in the loop, in Hz. A few Hz are enough to smear the
+    /// resonances and to allow higher decays without howling. 0 disables them.
+    pub fn set_frequency_shift(&mut self, shift: f32) {
+        for s in self.frequency_shifters.iter_mut() {
+            if s.shift() == 0. && shift != 0. {
+                s.reset();
             }
+            s.set_shift(shift);
+        }
+    }
 
-            let mut l = input[ii] * (1.0 - self.drywet) + self.drywet * (self.feedback[0] + self.feedback[2]);
-            let mut r = input[ii] * (1.0 - self.drywet) + self.drywet * (self.feedback[1] + self.feedback[3]);
+    /// Gate the wet signal, for gated reverbs: see `Gate` for the other settings. The gate and
+    /// the ducker see the peak level of the key, followed with a 0.1 ms attack and a 10 ms
+    /// release.
+    pub fn set_gate(&mut self, enabled: bool) {
+        if enabled && !self.gate_enabled {
+            if !self.ducking_enabled {
+                self.key_follower.reset();
+            }
+            self.gate.reset();
+        }
+        self.gate_enabled = enabled;
+    }
+    /// The gate on the wet signal, to change its settings.
+    pub fn gate_mut(&mut self) -> &mut Gate {
+        &mut self.gate
+    }
+    /// Duck the wet signal under the key, so that the tail comes up in the gaps: see `Ducker`
+    /// for the other settings.
+    pub fn set_ducking(&mut self, enabled: bool) {
+        if enabled && !self.ducking_enabled {
+            if !self.gate_enabled {
+                self.key_follower.reset();
+            }
+            self.ducker.reset();
+        }
+        self.ducking_enabled = enabled;
+    }
+    /// The ducker on the wet signal, to change its settings.
+    pub fn ducker_mut(&mut self) -> &mut Ducker {
+        &mut self.ducker
+    }
+    // Apply the dynamics on the wet signal, keyed by `key`.
+    fn process_wet(&mut self, key: f32, wet: (f32, f32)) -> (f32, f32) {
+        if !self.gate_enabled && !self.ducking_enabled {
+            return wet;
+        }
+        let level = self.key_follower.process(key);
+        let mut gain = 1.;
+        if self.gate_enabled {
+            gain *= self.gate.process(level);
+        }
+        if self.ducking_enabled {
+            gain *= self.ducker.process(level);
+        }
+        (wet.0 * gain, wet.1 * gain)
+    }
+    // Run one frame through the network, the left input feeding the even delay lines and the
+    // right input the odd ones. Returns the stereo wet signal.
+    fn process_frame(&mut self, l: f32, r: f32) -> (f32, f32) {
+        let input = [l, r, l, r];
+        let mut a = [0.; 4];
+        let mut b = [0.; 4];
 
-            let mid = (l + r) / 2.;
-            let mut side = (l - r) / 2.;
+        for i in 0..4 {
+            self.lowpasses[i].process(input[i] + self.feedback[i], &mut a[i]);
+        }
+        for i in 0..4 {
+            self.all_passes[i].process(a[i], &mut b[i]);
+        }
+        for i in 0..4 {
+            self.delays[i].process(b[i], &mut a[i]);
+        }
+        for i in 0..4 {
+            self.softclip.process(a[i], &mut b[i]);
+        }
+        if self.shimmer_mix > 0. {
+            for (s, v) in self.shifters.iter_mut().zip(b.iter_mut()) {
+                let mut shifted = 0.0;
+                s.process(*v, &mut shifted);
+                *v = (1. - self.shimmer_mix) * *v + self.shimmer_mix * self.shimmer_feedback * shifted;
+            }
+        }
+        if self.frequency_shifters[0].shift() != 0. {
+            for (s, v) in self.frequency_shifters.iter_mut().zip(b.iter_mut()) {
+                let input = *v;
+                s.process(input, v);
+            }
+        }
 
-            side *= self.width;
+        a = matrix_vector_multiply(&b, &self.feedback_matrix);
 
-            l = mid + side;
-            r = mid - side;
+        let feedback_amount = if self.dynamic_decay {
+            self.dynamic_feedback
+        } else {
+            self.feedback_amount
+        };
+        for i in 0..4 {
+            self.feedback[i] = a[i] * feedback_amount;
+        }
 
-            output[idx] = l;
-            output[idx + 1] = r;
-            idx += 2;
+        (
+            self.feedback[0] + self.feedback[2],
+            self.feedback[1] + self.feedback[3],
+        )
+    }
+    pub fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        self.process_sidechain(input, input, output);
+    }
+    /// Like `process`, with the dynamics on the wet signal keyed from `sidechain`, a mono
+    /// signal of the same length as `input`, instead of the input. Panics if the lengths differ.
+    pub fn process_sidechain(&mut self, input: &[f32], sidechain: &[f32], output: &mut [f32]) {
+        assert_eq!(input.len(), sidechain.len(), "sidechain and input lengths differ");
+        for ((i, k), o) in input.iter().zip(sidechain.iter()).zip(output.chunks_mut(2)) {
+            let mut predelayed = 0.0;
+            self.pre_delays[0].process(*i, &mut predelayed);
+            self.modulate_decay(*k);
+            let wet = self.process_frame(predelayed, predelayed);
+            let (wet_l, wet_r) = self.process_wet(*k, wet);
+            let (l, r) = stereo_output(*i, *i, wet_l, wet_r, self.drywet, self.width);
+            o[0] = l;
+            o[1] = r;
         }
     }
+    pub fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        self.process_stereo_keyed(input, None, output);
+    }
+    /// Like `process_stereo`, with the dynamics on the wet signal keyed from `sidechain`, a
+    /// mono signal with as many frames as `input`. Panics if the lengths differ.
+    pub fn process_stereo_sidechain(
+        &mut self,
+        input: &[f32],
+        sidechain: &[f32],
+        output: &mut [f32],
+    ) {
+        assert_eq!(input.len() / 2, sidechain.len(), "sidechain and input lengths differ");
+        self.process_stereo_keyed(input, Some(sidechain), output);
+    }
+    // Without a sidechain, the key is the mid of the input.
+    fn process_stereo_keyed(&mut self, input: &[f32], sidechain: Option<&[f32]>, output: &mut [f32]) {
+        for (f, (i, o)) in input.chunks(2).zip(output.chunks_mut(2)).enumerate() {
+            let mut predelayed = [0.0; 2];
+            self.pre_delays[0].process(i[0], &mut predelayed[0]);
+            self.pre_delays[1].process(i[1], &mut predelayed[1]);
+            let key = match sidechain {
+                Some(sidechain) => sidechain[f],
+                None => (i[0] + i[1]) / 2.,
+            };
+            self.modulate_decay(key);
+            let wet = self.process_frame(predelayed[0], predelayed[1]);
+            let (wet_l, wet_r) = self.process_wet(key, wet);
+            let (l, r) = stereo_output(i[0], i[1], wet_l, wet_r, self.drywet, self.width);
+            o[0] = l;
+            o[1] = r;
+        }
+    }
+    pub fn reset(&mut self) {
+        self.feedback = [0.; 4];
+        self.pre_delays.iter_mut().for_each(|d| d.reset());
+        self.all_passes.iter_mut().for_each(|a| a.reset());
+        self.delays.iter_mut().for_each(|d| d.reset());
+        self.lowpasses.iter_mut().for_each(|l| l.reset());
+        self.shifters.iter_mut().for_each(|s| s.reset());
+        self.frequency_shifters.iter_mut().for_each(|s| s.reset());
+        self.key_follower.reset();
+        self.gate.reset();
+        self.ducker.reset();
+        self.decay_follower.reset();
+        self.decay_countdown = 0;
+    }
     pub fn sample_rate(&self) -> f32 {
         self.sample_rate
     }
 
     pub fn tail_size(&self) -> isize {
+        if self.dynamic_decay {
+            return (self.sample_rate * self.idle_rt60.max(self.active_rt60)) as isize;
+        }
         // arbitrary
         (self.sample_rate * self.feedback_amount * 10.) as isize
     }
 }
 
+impl Reverb for FDNReverb {
+    fn name(&self) -> &'static str {
+        "fdn"
+    }
+    fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        FDNReverb::process(self, input, output);
+    }
+    fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        FDNReverb::process_stereo(self, input, output);
+    }
+    fn reset(&mut self) {
+        FDNReverb::reset(self);
+    }
+    fn tail_size(&self) -> isize {
+        FDNReverb::tail_size(self)
+    }
+    fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        // Keep the current delay times, that depend on the history of size and progression
+        // changes.
+        let ratio = sample_rate / self.sample_rate;
+        let mut fresh = FDNReverb::new(sample_rate);
+        for (d, old) in fresh.delays.iter_mut().zip(self.delays.iter()) {
+            d.set_duration((old.duration() as f32 * ratio) as usize);
+        }
+        for (a, old) in fresh.all_passes.iter_mut().zip(self.all_passes.iter()) {
+            a.set_delay(old.delay() as f32 * ratio);
+        }
+        fresh.update_loop_frames();
+        fresh.size = self.size;
+        fresh.progression = self.progression;
+        fresh.width = self.width;
+        fresh.drywet = self.drywet;
+        fresh.set_pre_delay(self.pre_delay);
+        fresh.set_decay(self.feedback_amount);
+        fresh.set_absorbtion(self.absorbtion);
+        fresh.set_hardness(self.softclip.hardness());
+        fresh.set_shimmer_mix(self.shimmer_mix);
+        fresh.set_shimmer_interval(self.shimmer_interval);
+        fresh.set_shimmer_feedback(self.shimmer_feedback);
+        fresh.set_frequency_shift(self.frequency_shifters[0].shift());
+        fresh.gate = self.gate.clone();
+        fresh.gate.set_sample_rate(sample_rate);
+        fresh.set_gate(self.gate_enabled);
+        fresh.ducker = self.ducker.clone();
+        fresh.ducker.set_sample_rate(sample_rate);
+        fresh.set_ducking(self.ducking_enabled);
+        fresh.decay_follower = self.decay_follower.clone();
+        fresh.decay_follower.set_sample_rate(sample_rate);
+        fresh.set_idle_rt60(self.idle_rt60);
+        fresh.set_active_rt60(self.active_rt60);
+        fresh.set_dynamic_decay(self.dynamic_decay);
+        *self = fresh;
+    }
+    fn parameters(&self) -> &'static [ParameterInfo] {
+        PARAMETERS
+    }
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        match index {
+            0 => self.set_pre_delay(value),
+            1 => self.set_absorbtion(value),
+            2 => self.set_size(value),
+            3 => self.set_decay(value),
+            4 => self.set_hardness(value),
+            5 => self.set_progression(value),
+            6 => self.set_width(value),
+            7 => self.set_drywet(value),
+            8 => self.set_shimmer_mix(value),
+            9 => self.set_shimmer_interval(value),
+            10 => self.set_shimmer_feedback(value),
+            11 => self.set_frequency_shift(value),
+            12 => self.set_gate(value >= 0.5),
+            13 => self.gate.set_threshold(value),
+            14 => self.gate.set_hold(value),
+            15 => self.gate.set_attack(value),
+            16 => self.gate.set_release(value),
+            17 => self.set_ducking(value >= 0.5),
+            18 => self.ducker.set_threshold(value),
+            19 => self.ducker.set_ratio(value),
+            20 => self.ducker.set_depth(value),
+            21 => self.ducker.set_attack(value),
+            22 => self.ducker.set_release(value),
+            23 => self.set_dynamic_decay(value >= 0.5),
+            24 => self.set_idle_rt60(value),
+            25 => self.set_active_rt60(value),
+            26 => self.set_decay_attack(value),
+            27 => self.set_decay_release(value),
+            _ => {}
+        }
+    }
+    fn parameter(&self, index: usize) -> f32 {
+        match index {
+            0 => self.pre_delay,
+            1 => self.absorbtion,
+            2 => self.size,
+            3 => self.decay(),
+            4 => self.softclip.hardness(),
+            5 => self.progression,
+            6 => self.width,
+            7 => self.drywet,
+            8 => self.shimmer_mix,
+            9 => self.shimmer_interval,
+            10 => self.shimmer_feedback,
+            11 => self.frequency_shifters[0].shift(),
+            12 => self.gate_enabled as u8 as f32,
+            13 => self.gate.threshold_db(),
+            14 => self.gate.hold_time(),
+            15 => self.gate.attack_time(),
+            16 => self.gate.release_time(),
+            17 => self.ducking_enabled as u8 as f32,
+            18 => self.ducker.threshold(),
+            19 => self.ducker.ratio(),
+            20 => self.ducker.depth(),
+            21 => self.ducker.attack_time(),
+            22 => self.ducker.release_time(),
+            23 => self.dynamic_decay as u8 as f32,
+            24 => self.idle_rt60,
+            25 => self.active_rt60,
+            26 => self.decay_follower.attack_time(),
+            27 => self.decay_follower.release_time(),
+            _ => 0.,
+        }
+    }
+}
+
 impl Default for FDNReverb {
     fn default() -> Self {
         FDNReverb::new(44100.)
@@ -226,8 +869,119 @@ impl Default for FDNReverb {
 
 #[cfg(test)]
 mod tests {
-    
+    use super::*;
 
     #[test]
     fn it_works() {}
+
+    fn gated() -> FDNReverb {
+        let mut reverb = FDNReverb::new(44100.);
+        reverb.set_size(10.);
+        reverb.set_drywet(1.);
+        reverb.set_gate(true);
+        let gate = reverb.gate_mut();
+        gate.set_threshold(-20.);
+        gate.set_hold(0.);
+        gate.set_attack(0.);
+        gate.set_release(0.);
+        reverb
+    }
+
+    #[test]
+    fn sidechain_keys_the_gate() {
+        let input: Vec<f32> = (0..8192).map(|i| if i < 64 { 0.5 } else { 0. }).collect();
+        let mut output = vec![0.; input.len() * 2];
+
+        let mut reverb = gated();
+        reverb.process_sidechain(&input, &vec![0.; input.len()], &mut output);
+        assert!(output.iter().all(|s| *s == 0.));
+
+        let mut reverb = gated();
+        reverb.process_sidechain(&input, &vec![1.; input.len()], &mut output);
+        let mut open = FDNReverb::new(44100.);
+        open.set_size(10.);
+        open.set_drywet(1.);
+        let mut expected = vec![0.; input.len() * 2];
+        open.process(&input, &mut expected);
+        assert!(expected.iter().any(|s| *s != 0.));
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn toggling_one_dynamic_keeps_the_other_running() {
+        let input: Vec<f32> = (0..8192).map(|i| ((i / 512) % 2) as f32 * 0.5).collect();
+        let (first, second) = input.split_at(4096);
+        let mut scratch = vec![0.; first.len() * 2];
+
+        let mut both = gated();
+        both.set_ducking(true);
+        both.process(first, &mut scratch);
+        let level = both.key_follower.envelope();
+        both.set_gate(false);
+        both.set_gate(true);
+        assert_eq!(both.key_follower.envelope(), level);
+        both.set_ducking(false);
+
+        let mut gate_only = gated();
+        gate_only.process(first, &mut scratch);
+
+        let mut output = vec![0.; second.len() * 2];
+        let mut expected = vec![0.; second.len() * 2];
+        both.process(second, &mut output);
+        gate_only.process(second, &mut expected);
+        assert_eq!(output, expected);
+    }
+
+    // Feedback amount the dynamic decay settles at with a key at `key`.
+    fn settled_feedback(key: f32) -> f32 {
+        let mut reverb = FDNReverb::new(44100.);
+        reverb.set_dynamic_decay(true);
+        reverb.set_idle_rt60(4.);
+        reverb.set_active_rt60(1.);
+        for _ in 0..44100 {
+            reverb.modulate_decay(key);
+        }
+        reverb.dynamic_feedback
+    }
+
+    fn feedback_for_rt60(rt60: f32) -> f32 {
+        let mut reverb = FDNReverb::new(44100.);
+        reverb.set_rt60(rt60);
+        reverb.decay()
+    }
+
+    #[test]
+    fn quiet_key_gives_the_idle_rt60() {
+        assert!((settled_feedback(0.) - feedback_for_rt60(4.)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn loud_key_gives_the_active_rt60() {
+        assert!((settled_feedback(1.) - feedback_for_rt60(1.)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn defaults_match_constructor() {
+        let reverb = FDNReverb::new(44100.);
+        let default = |name: &str| {
+            PARAMETERS
+                .iter()
+                .find(|p| p.name == name)
+                .unwrap()
+                .default
+        };
+        assert_eq!(default("pre-delay"), reverb.pre_delay);
+        assert_eq!(default("absorption"), reverb.absorbtion);
+        assert_eq!(default("size"), reverb.size);
+        assert_eq!(default("decay"), reverb.decay());
+        assert_eq!(default("hardness"), reverb.softclip.hardness());
+        assert_eq!(default("progression"), reverb.progression);
+        assert_eq!(default("width"), reverb.width);
+        assert_eq!(default("dry/wet"), reverb.drywet);
+        assert_eq!(default("shimmer mix"), reverb.shimmer_mix);
+        assert_eq!(default("shimmer interval"), reverb.shimmer_interval);
+        assert_eq!(default("shimmer feedback"), reverb.shimmer_feedback);
+        assert_eq!(default("idle rt60"), reverb.idle_rt60);
+        assert_eq!(default("active rt60"), reverb.active_rt60);
+    }
 }
@@ -23,6 +23,9 @@ impl OnePoleLowPass {
         self.b1 = (-2.0 * PI * normalized_freq).exp();
         self.a0 = 1.0 - self.b1;
     }
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+    }
     pub fn process(&mut self, input: f32, output: &mut f32) {
         self.z1 = input * self.a0 + self.z1 * self.b1;
         *output = self.z1;
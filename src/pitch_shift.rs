@@ -0,0 +1,60 @@
+use crate::delay_line::DelayLine;
+use crate::utils::clamp;
+
+/// Delay-line pitch shifter: two read heads sweep a window of the recent input at a speed that
+/// depends on the interval, half a window apart, each faded in and out with a Hann window. The
+/// two windows sum to one, so the output is never louder than the input, which keeps it safe in
+/// a feedback loop.
+pub struct PitchShifter {
+    buffer: DelayLine,
+    window: f32,
+    max_window: f32,
+    ratio: f32,
+    phase: f32,
+}
+
+impl PitchShifter {
+    /// `max_window` is in seconds, the window is set to this value initially.
+    pub fn new(max_window: f32, sample_rate: f32) -> PitchShifter {
+        let frames = max_window * sample_rate;
+        PitchShifter {
+            buffer: DelayLine::new(frames as usize + 2),
+            window: frames,
+            max_window: frames,
+            ratio: 1.,
+            phase: 0.,
+        }
+    }
+
+    /// Interval in semitones, negative to shift down.
+    pub fn set_interval(&mut self, semitones: f32) {
+        self.ratio = 2.0f32.powf(semitones / 12.);
+    }
+
+    /// Length of the window, in frames. Longer windows smear transients, shorter ones sound
+    /// rougher.
+    pub fn set_window(&mut self, frames: f32) {
+        self.window = clamp(frames, 2., self.max_window);
+    }
+
+    /// Start the sweep at `phase`, in [0, 1), to decorrelate several shifters.
+    pub fn set_phase(&mut self, phase: f32) {
+        self.phase = phase.fract();
+    }
+
+    pub fn reset(&mut self) {
+        self.buffer.reset();
+    }
+
+    pub fn process(&mut self, input: f32, output: &mut f32) {
+        self.buffer.write(input);
+        // The delay changes by `1 - ratio` per frame: it shrinks to shift up.
+        self.phase += (1. - self.ratio) / self.window;
+        self.phase -= self.phase.floor();
+        let other = (self.phase + 0.5).fract();
+        let fade = (std::f32::consts::PI * self.phase).sin();
+        let gain = fade * fade;
+        *output = gain * self.buffer.tap(self.phase * self.window)
+            + (1. - gain) * self.buffer.tap(other * self.window);
+    }
+}
@@ -0,0 +1,362 @@
+use crate::allpass::Allpass;
+use crate::delay_line::DelayLine;
+use crate::onepolelowpass::OnePoleLowPass;
+use crate::reverb::{copy_parameters, ParameterInfo, Reverb, DRY_WET, PRE_DELAY, WIDTH};
+use crate::utils::{clamp, stereo_output};
+use std::f32::consts::PI;
+
+// Dattorro, "Effect Design, Part 1: Reverberator and Other Filters", 1997. All the delay times
+// are given in frames at 29761Hz.
+const REFERENCE_RATE: f32 = 29761.;
+const INPUT_DIFFUSERS: [(f32, f32); 4] = [(142., 0.75), (107., 0.75), (379., 0.625), (277., 0.625)];
+const DECAY_DIFFUSION_1: f32 = -0.7;
+const MODULATED_ALLPASSES: [f32; 2] = [672., 908.];
+const FIRST_DELAYS: [f32; 2] = [4453., 4217.];
+const DECAY_ALLPASSES: [f32; 2] = [1800., 2656.];
+const SECOND_DELAYS: [f32; 2] = [3720., 3163.];
+const LFO_FREQUENCY: f32 = 1.0;
+// Output taps: (tank half, node, position, sign). Nodes are 0 for the first delay, 1 for the
+// decay allpass, and 2 for the second delay of a tank half.
+const LEFT_TAPS: [(usize, usize, f32, f32); 7] = [
+    (1, 0, 266., 1.),
+    (1, 0, 2974., 1.),
+    (1, 1, 1913., -1.),
+    (1, 2, 1996., 1.),
+    (0, 0, 1990., -1.),
+    (0, 1, 187., -1.),
+    (0, 2, 1066., -1.),
+];
+const RIGHT_TAPS: [(usize, usize, f32, f32); 7] = [
+    (0, 0, 353., 1.),
+    (0, 0, 3627., 1.),
+    (0, 1, 1228., -1.),
+    (0, 2, 2673., 1.),
+    (1, 0, 2111., -1.),
+    (1, 1, 335., -1.),
+    (1, 2, 121., -1.),
+];
+
+const PARAMETERS: &[ParameterInfo] = &[
+    PRE_DELAY,
+    ParameterInfo {
+        name: "bandwidth",
+        default: 10000.,
+        min: 100.,
+        max: 20000.,
+        unit: "Hz",
+    },
+    ParameterInfo {
+        name: "damping",
+        default: 8000.,
+        min: 100.,
+        max: 20000.,
+        unit: "Hz",
+    },
+    ParameterInfo {
+        name: "decay",
+        default: 0.5,
+        min: 0.,
+        max: 0.99,
+        unit: "",
+    },
+    ParameterInfo {
+        name: "excursion",
+        default: 0.5,
+        min: 0.,
+        max: 1.,
+        unit: "ms",
+    },
+    WIDTH,
+    DRY_WET,
+];
+
+// One half of the figure-eight tank.
+struct TankHalf {
+    modulated_allpass: Allpass,
+    first_delay: DelayLine,
+    damping: OnePoleLowPass,
+    decay_allpass: Allpass,
+    second_delay: DelayLine,
+    modulated_delay: f32,
+}
+
+impl TankHalf {
+    fn new(half: usize, sample_rate: f32, damping: f32) -> TankHalf {
+        let scale = sample_rate / REFERENCE_RATE;
+        let delay_line = |frames: f32| {
+            let frames = (frames * scale) as usize;
+            let mut d = DelayLine::new(frames + 1);
+            d.set_duration(frames);
+            d
+        };
+        TankHalf {
+            modulated_allpass: Allpass::new(
+                MODULATED_ALLPASSES[half] / REFERENCE_RATE,
+                DECAY_DIFFUSION_1,
+                sample_rate,
+            ),
+            first_delay: delay_line(FIRST_DELAYS[half]),
+            damping: OnePoleLowPass::new(damping, sample_rate),
+            decay_allpass: Allpass::new(DECAY_ALLPASSES[half] / REFERENCE_RATE, 0.5, sample_rate),
+            second_delay: delay_line(SECOND_DELAYS[half]),
+            modulated_delay: MODULATED_ALLPASSES[half] * scale,
+        }
+    }
+    fn process(&mut self, input: f32, modulation: f32, decay: f32) -> f32 {
+        let mut a = 0.;
+        let mut b = 0.;
+        self.modulated_allpass
+            .process_modulated(input, self.modulated_delay + modulation, &mut a);
+        self.first_delay.process(a, &mut b);
+        self.damping.process(b, &mut a);
+        self.decay_allpass.process(a * decay, &mut b);
+        self.second_delay.process(b, &mut a);
+        a * decay
+    }
+    fn tap(&self, node: usize, position: f32) -> f32 {
+        match node {
+            0 => self.first_delay.tap(position),
+            1 => self.decay_allpass.tap(position),
+            _ => self.second_delay.tap(position),
+        }
+    }
+    fn reset(&mut self) {
+        self.modulated_allpass.reset();
+        self.first_delay.reset();
+        self.damping.reset();
+        self.decay_allpass.reset();
+        self.second_delay.reset();
+    }
+}
+
+/// Dattorro's plate reverb: an input diffuser of four allpasses, feeding a figure-eight tank
+/// with modulated allpasses, from which the stereo output is tapped.
+pub struct PlateReverb {
+    drywet: f32,
+    width: f32,
+    pre_delay: DelayLine,
+    pre_delay_time: f32,
+    bandwidth_filter: OnePoleLowPass,
+    bandwidth: f32,
+    diffusers: [Allpass; 4],
+    tank: [TankHalf; 2],
+    tank_outputs: [f32; 2],
+    damping: f32,
+    decay: f32,
+    excursion: f32,
+    lfo_phase: f32,
+    sample_rate: f32,
+}
+
+impl PlateReverb {
+    pub fn new(sample_rate: f32) -> PlateReverb {
+        let mut pre_delay = DelayLine::new((150. * sample_rate / 1000.) as usize);
+        pre_delay.set_duration(0);
+        let diffuser = |i: usize| {
+            let (frames, gain) = INPUT_DIFFUSERS[i];
+            Allpass::new(frames / REFERENCE_RATE, gain, sample_rate)
+        };
+        let bandwidth = 10000.;
+        let damping = 8000.;
+        PlateReverb {
+            drywet: 0.3,
+            width: 1.0,
+            pre_delay,
+            pre_delay_time: 0.,
+            bandwidth_filter: OnePoleLowPass::new(bandwidth, sample_rate),
+            bandwidth,
+            diffusers: [diffuser(0), diffuser(1), diffuser(2), diffuser(3)],
+            tank: [
+                TankHalf::new(0, sample_rate, damping),
+                TankHalf::new(1, sample_rate, damping),
+            ],
+            tank_outputs: [0.; 2],
+            damping,
+            decay: 0.5,
+            excursion: 0.5,
+            lfo_phase: 0.,
+            sample_rate,
+        }
+    }
+
+    pub fn set_pre_delay(&mut self, pre_delay: f32) {
+        let pre_delay_frames = (pre_delay * self.sample_rate / 1000.) as usize;
+        self.pre_delay_time = pre_delay;
+        self.pre_delay.set_duration(pre_delay_frames);
+    }
+
+    pub fn set_bandwidth(&mut self, bandwidth: f32) {
+        self.bandwidth = bandwidth;
+        self.bandwidth_filter.set_frequency(bandwidth);
+    }
+
+    pub fn set_damping(&mut self, damping: f32) {
+        self.damping = damping;
+        for half in self.tank.iter_mut() {
+            half.damping.set_frequency(damping);
+        }
+    }
+
+    pub fn set_decay(&mut self, decay: f32) {
+        self.decay = clamp(decay, 0., 0.99);
+        // Dattorro ties the diffusion of the second tank allpasses to the decay.
+        let decay_diffusion_2 = clamp(self.decay + 0.15, 0.25, 0.5);
+        for half in self.tank.iter_mut() {
+            half.decay_allpass.set_gain(decay_diffusion_2);
+        }
+    }
+
+    /// Peak modulation of the tank allpasses, in ms.
+    pub fn set_excursion(&mut self, excursion: f32) {
+        self.excursion = excursion;
+    }
+
+    pub fn set_width(&mut self, width: f32) {
+        self.width = width;
+    }
+
+    pub fn set_drywet(&mut self, drywet: f32) {
+        self.drywet = drywet;
+    }
+
+    fn process_frame(&mut self, input: f32) -> (f32, f32) {
+        let mut predelayed = 0.0;
+        let mut a = 0.0;
+        self.pre_delay.process(input, &mut predelayed);
+        self.bandwidth_filter.process(predelayed, &mut a);
+        for d in self.diffusers.iter_mut() {
+            let mut b = 0.0;
+            d.process(a, &mut b);
+            a = b;
+        }
+
+        let excursion = self.excursion * self.sample_rate / 1000.;
+        let phase = 2. * PI * self.lfo_phase;
+        let modulation = [excursion * phase.sin(), excursion * phase.cos()];
+        self.lfo_phase = (self.lfo_phase + LFO_FREQUENCY / self.sample_rate).fract();
+
+        let left = self.tank[0].process(a + self.tank_outputs[1], modulation[0], self.decay);
+        let right = self.tank[1].process(a + self.tank_outputs[0], modulation[1], self.decay);
+        self.tank_outputs = [left, right];
+
+        let scale = self.sample_rate / REFERENCE_RATE;
+        let output = |taps: &[(usize, usize, f32, f32)]| {
+            0.6 * taps
+                .iter()
+                .map(|(half, node, position, sign)| {
+                    sign * self.tank[*half].tap(*node, position * scale)
+                })
+                .sum::<f32>()
+        };
+        (output(&LEFT_TAPS), output(&RIGHT_TAPS))
+    }
+
+    pub fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        for (i, o) in input.iter().zip(output.chunks_mut(2)) {
+            let (wet_l, wet_r) = self.process_frame(*i);
+            let (l, r) = stereo_output(*i, *i, wet_l, wet_r, self.drywet, self.width);
+            o[0] = l;
+            o[1] = r;
+        }
+    }
+
+    /// The tank is fed with the mid signal.
+    pub fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        for (i, o) in input.chunks(2).zip(output.chunks_mut(2)) {
+            let (wet_l, wet_r) = self.process_frame((i[0] + i[1]) / 2.);
+            let (l, r) = stereo_output(i[0], i[1], wet_l, wet_r, self.drywet, self.width);
+            o[0] = l;
+            o[1] = r;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pre_delay.reset();
+        self.bandwidth_filter.reset();
+        self.diffusers.iter_mut().for_each(|d| d.reset());
+        self.tank.iter_mut().for_each(|t| t.reset());
+        self.tank_outputs = [0.; 2];
+        self.lfo_phase = 0.;
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    pub fn tail_size(&self) -> isize {
+        // Time for the tank to decay by 60dB, one trip around the figure-eight applying the
+        // decay four times.
+        let trip: f32 = FIRST_DELAYS
+            .iter()
+            .chain(SECOND_DELAYS.iter())
+            .chain(MODULATED_ALLPASSES.iter())
+            .chain(DECAY_ALLPASSES.iter())
+            .sum::<f32>()
+            * self.sample_rate
+            / REFERENCE_RATE;
+        if self.decay <= 0. {
+            return trip as isize;
+        }
+        let trips = -3. / (4. * self.decay.log10());
+        (trip * trips) as isize
+    }
+}
+
+impl Reverb for PlateReverb {
+    fn name(&self) -> &'static str {
+        "plate"
+    }
+    fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        PlateReverb::process(self, input, output);
+    }
+    fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        PlateReverb::process_stereo(self, input, output);
+    }
+    fn reset(&mut self) {
+        PlateReverb::reset(self);
+    }
+    fn tail_size(&self) -> isize {
+        PlateReverb::tail_size(self)
+    }
+    fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        let mut fresh = PlateReverb::new(sample_rate);
+        copy_parameters(self, &mut fresh);
+        *self = fresh;
+    }
+    fn parameters(&self) -> &'static [ParameterInfo] {
+        PARAMETERS
+    }
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        match index {
+            0 => self.set_pre_delay(value),
+            1 => self.set_bandwidth(value),
+            2 => self.set_damping(value),
+            3 => self.set_decay(value),
+            4 => self.set_excursion(value),
+            5 => self.set_width(value),
+            6 => self.set_drywet(value),
+            _ => {}
+        }
+    }
+    fn parameter(&self, index: usize) -> f32 {
+        match index {
+            0 => self.pre_delay_time,
+            1 => self.bandwidth,
+            2 => self.damping,
+            3 => self.decay,
+            4 => self.excursion,
+            5 => self.width,
+            6 => self.drywet,
+            _ => 0.,
+        }
+    }
+}
+
+impl Default for PlateReverb {
+    fn default() -> Self {
+        PlateReverb::new(44100.)
+    }
+}
@@ -0,0 +1,162 @@
+use crate::reverb::Reverb;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum PresetError {
+    Io(io::Error),
+    /// A line that isn't empty, a comment, or `name = value`.
+    Syntax {
+        line: usize,
+        text: String,
+    },
+    /// The engine of the preset doesn't have a parameter of that name.
+    UnknownParameter(String),
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PresetError::Io(e) => write!(f, "{}", e),
+            PresetError::Syntax { line, text } => {
+                write!(f, "line {}: expected `name = value`, got `{}`", line, text)
+            }
+            PresetError::UnknownParameter(name) => write!(f, "unknown parameter `{}`", name),
+        }
+    }
+}
+
+impl Error for PresetError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PresetError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PresetError {
+    fn from(e: io::Error) -> PresetError {
+        PresetError::Io(e)
+    }
+}
+
+/// An engine and values for some of its parameters, by name, in the unit of their setters. The
+/// file format is one `name = value` per line, with `engine = <engine>` to pick the engine, and
+/// `#` to start a comment:
+///
+/// ```text
+/// # long dark hall
+/// engine = fdn
+/// size = 80
+/// absorption = 2500
+/// dry/wet = 0.4
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Preset {
+    pub engine: Option<String>,
+    pub parameters: Vec<(String, f32)>,
+}
+
+impl Preset {
+    pub fn load(path: &Path) -> Result<Preset, PresetError> {
+        Preset::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Preset, PresetError> {
+        let mut preset = Preset::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let syntax_error = || PresetError::Syntax {
+                line: i + 1,
+                text: line.to_string(),
+            };
+            let mut split = line.splitn(2, '=');
+            let name = split.next().unwrap().trim();
+            let value = split.next().ok_or_else(syntax_error)?.trim();
+            if name == "engine" {
+                preset.engine = Some(value.to_string());
+            } else {
+                let value = value.parse().map_err(|_| syntax_error())?;
+                preset.parameters.push((name.to_string(), value));
+            }
+        }
+        Ok(preset)
+    }
+
+    /// Sets the parameters on an engine. Nothing is set if one of the parameters is unknown.
+    pub fn apply(&self, reverb: &mut dyn Reverb) -> Result<(), PresetError> {
+        let mut indices = Vec::with_capacity(self.parameters.len());
+        for (name, value) in self.parameters.iter() {
+            match reverb.parameter_index(name) {
+                Some(index) => indices.push((index, *value)),
+                None => return Err(PresetError::UnknownParameter(name.clone())),
+            }
+        }
+        for (index, value) in indices {
+            reverb.set_parameter(index, value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FDNReverb;
+
+    #[test]
+    fn parses_values_engine_and_comments() {
+        let preset = Preset::parse(
+            "# a hall\n\
+             engine = fdn\n\
+             \n\
+             size = 80 # meters\n\
+             dry/wet=0.4\n",
+        )
+        .unwrap();
+        assert_eq!(preset.engine.as_deref(), Some("fdn"));
+        assert_eq!(
+            preset.parameters,
+            vec![("size".to_string(), 80.), ("dry/wet".to_string(), 0.4)]
+        );
+    }
+
+    #[test]
+    fn syntax_errors_have_line_numbers() {
+        match Preset::parse("size = 80\n# comment\nsize 80\n") {
+            Err(PresetError::Syntax { line, text }) => {
+                assert_eq!(line, 3);
+                assert_eq!(text, "size 80");
+            }
+            other => panic!("{:?}", other),
+        }
+        match Preset::parse("\nsize = big\n") {
+            Err(PresetError::Syntax { line: 2, .. }) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn nothing_is_applied_with_an_unknown_parameter() {
+        let mut reverb = FDNReverb::new(44100.);
+        let preset = Preset::parse("decay = 0.2\nloudness = 11\n").unwrap();
+        match preset.apply(&mut reverb) {
+            Err(PresetError::UnknownParameter(name)) => assert_eq!(name, "loudness"),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(reverb.decay(), 0.8);
+
+        Preset::parse("decay = 0.2\n")
+            .unwrap()
+            .apply(&mut reverb)
+            .unwrap();
+        assert_eq!(reverb.decay(), 0.2);
+    }
+}
@@ -0,0 +1,169 @@
+use crate::random::Random;
+
+// Error feedback filters: the quantization noise is shaped by `1 - sum(h[k] z^-(k + 1))`.
+const FIRST_ORDER: &[f32] = &[1.];
+// Lipshitz et al., "Minimally audible noise shaping", E-weighted, for 44.1kHz.
+const LIPSHITZ: &[f32] = &[2.033, -2.165, 1.959, -1.590, 0.6149];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoiseShaping {
+    None,
+    /// Pushes the noise up, +6dB at Nyquist and less and less towards DC.
+    FirstOrder,
+    /// Pushes the noise out of the range where the ear is most sensitive.
+    Lipshitz,
+}
+
+impl NoiseShaping {
+    fn filter(self) -> &'static [f32] {
+        match self {
+            NoiseShaping::None => &[],
+            NoiseShaping::FirstOrder => FIRST_ORDER,
+            NoiseShaping::Lipshitz => LIPSHITZ,
+        }
+    }
+}
+
+/// Converts float samples in [-1, 1] to integers of a given bit depth. Overs saturate instead
+/// of wrapping around, and the samples are dithered with triangular noise of two LSBs peak to
+/// peak so that the quantization error is not correlated with the signal, which is what makes
+/// it audible as distortion on quiet signals such as reverb tails. The error can then be shaped
+/// towards frequencies where it is less audible.
+pub struct Quantizer {
+    max: f32,
+    dither: bool,
+    shaping: NoiseShaping,
+    random: Random,
+    // Past quantization errors, most recent first, for each channel.
+    errors: Vec<[f32; 5]>,
+    clipped: usize,
+}
+
+impl Quantizer {
+    pub fn new(bits: u16, channels: usize) -> Quantizer {
+        Quantizer {
+            max: ((1u64 << (bits - 1)) - 1) as f32,
+            dither: true,
+            shaping: NoiseShaping::None,
+            random: Random::new(1),
+            errors: vec![[0.; 5]; channels],
+            clipped: 0,
+        }
+    }
+
+    pub fn set_dither(&mut self, dither: bool) {
+        self.dither = dither;
+    }
+
+    pub fn set_noise_shaping(&mut self, shaping: NoiseShaping) {
+        self.shaping = shaping;
+        self.reset();
+    }
+
+    /// Number of samples that have been saturated.
+    pub fn clipped(&self) -> usize {
+        self.clipped
+    }
+
+    pub fn reset(&mut self) {
+        self.errors.iter_mut().for_each(|e| *e = [0.; 5]);
+        self.clipped = 0;
+    }
+
+    pub fn process_sample(&mut self, channel: usize, input: f32) -> i32 {
+        let errors = &mut self.errors[channel];
+        let mut wanted = input * self.max;
+        for (h, e) in self.shaping.filter().iter().zip(errors.iter()) {
+            wanted -= h * e;
+        }
+        let dither = if self.dither {
+            self.random.next_f32() - self.random.next_f32()
+        } else {
+            0.
+        };
+        let quantized = (wanted + dither).round();
+        // Only the quantization error is fed back: feeding back the clipping error would make
+        // the filter ring for a long time after an over.
+        errors.rotate_right(1);
+        errors[0] = quantized - wanted;
+        if quantized > self.max || quantized < -self.max - 1. {
+            self.clipped += 1;
+        }
+        quantized.max(-self.max - 1.).min(self.max) as i32
+    }
+
+    /// Quantizes interleaved samples.
+    pub fn process(&mut self, input: &[f32], output: &mut [i32]) {
+        let channels = self.errors.len();
+        for (frame_in, frame_out) in input.chunks(channels).zip(output.chunks_mut(channels)) {
+            for (c, (i, o)) in frame_in.iter().zip(frame_out.iter_mut()).enumerate() {
+                *o = self.process_sample(c, *i);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Quantization noise of a quiet sine, in LSBs.
+    fn noise(quantizer: &mut Quantizer) -> Vec<f32> {
+        (0..20000)
+            .map(|i| {
+                let x = 0.001 * (i as f32 * 0.01).sin();
+                quantizer.process_sample(0, x) as f32 - x * 32767.
+            })
+            .collect()
+    }
+
+    // Correlation of the noise with itself one sample later: positive for low frequencies,
+    // negative for high frequencies.
+    fn lag_one_correlation(noise: &[f32]) -> f32 {
+        let power: f32 = noise.iter().map(|n| n * n).sum();
+        noise.windows(2).map(|w| w[0] * w[1]).sum::<f32>() / power
+    }
+
+    #[test]
+    fn saturates() {
+        let mut quantizer = Quantizer::new(16, 2);
+        let mut output = [0; 6];
+        quantizer.process(&[2., -2., 1.5, -1.01, 0.5, -0.5], &mut output);
+        assert_eq!(&output[..4], &[32767, -32768, 32767, -32768]);
+        assert_eq!(quantizer.clipped(), 4);
+
+        let mut quantizer = Quantizer::new(24, 1);
+        assert_eq!(quantizer.process_sample(0, 10.), (1 << 23) - 1);
+        assert_eq!(quantizer.process_sample(0, -10.), -(1 << 23));
+    }
+
+    #[test]
+    fn dither_stays_within_one_lsb() {
+        let mut quantizer = Quantizer::new(16, 1);
+        let mut dithered = false;
+        for i in 0..10000 {
+            let x = (i as f32 * 0.37).sin() * 0.01;
+            let exact = (x * 32767.).round() as i32;
+            let quantized = quantizer.process_sample(0, x);
+            assert!((quantized - exact).abs() <= 1);
+            dithered |= quantized != exact;
+        }
+        assert!(dithered);
+
+        quantizer.set_dither(false);
+        assert_eq!(quantizer.process_sample(0, 0.25), 8192);
+    }
+
+    #[test]
+    fn noise_shaping_pushes_the_noise_up() {
+        let mut quantizer = Quantizer::new(16, 1);
+        let flat = lag_one_correlation(&noise(&mut quantizer));
+        assert!(flat.abs() < 0.05, "{}", flat);
+
+        for &shaping in &[NoiseShaping::FirstOrder, NoiseShaping::Lipshitz] {
+            quantizer.set_noise_shaping(shaping);
+            let shaped = lag_one_correlation(&noise(&mut quantizer));
+            assert!(shaped < -0.3, "{:?}: {}", shaping, shaped);
+        }
+    }
+}
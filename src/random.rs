@@ -0,0 +1,28 @@
+/// xorshift32 pseudo-random number generator: cheap, deterministic for a given seed, and more
+/// than good enough for audio.
+pub struct Random {
+    state: u32,
+}
+
+impl Random {
+    pub fn new(seed: u32) -> Random {
+        // The state must never be zero.
+        Random {
+            state: if seed == 0 { 0x9E37_79B9 } else { seed },
+        }
+    }
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+    /// Uniform in [0, 1).
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+    /// Uniform in [-1, 1).
+    pub fn bipolar(&mut self) -> f32 {
+        self.next_f32() * 2. - 1.
+    }
+}
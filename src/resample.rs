@@ -0,0 +1,181 @@
+use std::f64::consts::PI;
+
+// Zero crossings of the sinc on each side, at the lower of the two rates.
+const ZERO_CROSSINGS: usize = 32;
+// Kernel values per input sample, linearly interpolated in between.
+const PHASES: usize = 512;
+// Cutoff of the lowpass, relative to the lower of the two Nyquist frequencies, to leave room
+// for the transition band.
+const ROLLOFF: f64 = 0.94;
+const KAISER_BETA: f64 = 9.;
+
+// Modified Bessel function of the first kind, order zero, for the Kaiser window.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.;
+    let mut term = 1.;
+    let mut k = 1.;
+    while term > sum * 1e-12 {
+        term *= (x / (2. * k)) * (x / (2. * k));
+        sum += term;
+        k += 1.;
+    }
+    sum
+}
+
+/// Band-limited sample-rate converter, for any ratio: each output sample is the input
+/// convolved with a Kaiser-windowed sinc centered on its position in the input. The sinc is
+/// stretched when converting down, so that it also filters out what would alias.
+pub struct Resampler {
+    from: u64,
+    to: u64,
+    // Half of the kernel, from its center, sampled `PHASES` times per input sample.
+    kernel: Vec<f32>,
+    // Half length of the kernel, in input samples.
+    half_width: usize,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32) -> Resampler {
+        let cutoff = ROLLOFF * f64::min(1., to_rate as f64 / from_rate as f64);
+        let half_width = (ZERO_CROSSINGS as f64 / cutoff).ceil() as usize;
+        let kernel = (0..=half_width * PHASES + 1)
+            .map(|i| {
+                let x = i as f64 / PHASES as f64;
+                let sinc = if i == 0 {
+                    1.
+                } else {
+                    (PI * cutoff * x).sin() / (PI * cutoff * x)
+                };
+                let w = x / half_width as f64;
+                let window = if w < 1. {
+                    bessel_i0(KAISER_BETA * (1. - w * w).sqrt()) / bessel_i0(KAISER_BETA)
+                } else {
+                    0.
+                };
+                (cutoff * sinc * window) as f32
+            })
+            .collect();
+        Resampler {
+            from: from_rate as u64,
+            to: to_rate as u64,
+            kernel,
+            half_width,
+        }
+    }
+
+    /// Number of frames the conversion of `frames` input frames gives.
+    pub fn output_frames(&self, frames: usize) -> usize {
+        ((frames as u64 * self.to).div_ceil(self.from)) as usize
+    }
+
+    fn kernel(&self, x: f32) -> f32 {
+        let position = x.abs() * PHASES as f32;
+        let index = position as usize;
+        if index + 1 >= self.kernel.len() {
+            return 0.;
+        }
+        let frac = position - index as f32;
+        self.kernel[index] + (self.kernel[index + 1] - self.kernel[index]) * frac
+    }
+
+    /// Converts a whole interleaved signal. The signal is considered silent before and after
+    /// the input.
+    pub fn process(&self, input: &[f32], channels: usize) -> Vec<f32> {
+        if self.from == self.to {
+            return input.to_vec();
+        }
+        let frames = input.len() / channels;
+        let output_frames = self.output_frames(frames);
+        let mut output = vec![0.0; output_frames * channels];
+        for (n, o) in output.chunks_mut(channels).enumerate() {
+            // Position in the input, kept as a fraction so that it doesn't drift.
+            let numerator = n as u64 * self.from;
+            let center = (numerator / self.to) as isize;
+            let frac = (numerator % self.to) as f32 / self.to as f32;
+            let first = std::cmp::max(center - self.half_width as isize + 1, 0);
+            let last = std::cmp::min(center + self.half_width as isize, frames as isize - 1);
+            for i in first..=last {
+                let gain = self.kernel((i - center) as f32 - frac);
+                let frame = &input[i as usize * channels..(i as usize + 1) * channels];
+                for (o, s) in o.iter_mut().zip(frame) {
+                    *o += s * gain;
+                }
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f64, rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (2. * PI * frequency * i as f64 / rate as f64).sin() as f32)
+            .collect()
+    }
+
+    #[test]
+    fn output_frames() {
+        let up = Resampler::new(44100, 48000);
+        assert_eq!(up.output_frames(44100), 48000);
+        assert_eq!(up.output_frames(3), 4);
+        assert_eq!(up.output_frames(0), 0);
+        let down = Resampler::new(96000, 48000);
+        assert_eq!(down.output_frames(5), 3);
+        assert_eq!(down.process(&[0.; 10], 2).len(), 6);
+    }
+
+    #[test]
+    fn unity_gain_at_dc() {
+        for &(from, to) in &[
+            (44100, 48000),
+            (48000, 44100),
+            (48000, 96000),
+            (96000, 48000),
+        ] {
+            let resampler = Resampler::new(from, to);
+            let output = resampler.process(&vec![1.; 4 * from as usize / 100], 1);
+            // Away from the edges, where the input starts and stops.
+            let margin = 2 * resampler.half_width * to as usize / from as usize + 2;
+            for o in &output[margin..output.len() - margin] {
+                assert!((o - 1.).abs() < 1e-4, "{} -> {}: {}", from, to, o);
+            }
+        }
+    }
+
+    #[test]
+    fn sine_accuracy() {
+        let resampler = Resampler::new(44100, 48000);
+        let input = sine(1000., 44100, 4410);
+        let output = resampler.process(&input, 1);
+        let expected = sine(1000., 48000, output.len());
+        let margin = 2 * resampler.half_width;
+        let error = output[margin..output.len() - margin]
+            .iter()
+            .zip(expected[margin..].iter())
+            .fold(0f32, |m, (o, e)| m.max((o - e).abs()));
+        assert!(error < 1e-3, "{}", error);
+    }
+
+    #[test]
+    fn rejects_above_nyquist() {
+        // 30kHz can't be represented at 48kHz.
+        let resampler = Resampler::new(96000, 48000);
+        let output = resampler.process(&sine(30000., 96000, 9600), 1);
+        let margin = resampler.half_width;
+        let peak = output[margin..output.len() - margin]
+            .iter()
+            .fold(0f32, |m, o| m.max(o.abs()));
+        assert!(peak < 1e-3, "{}", peak);
+    }
+
+    #[test]
+    fn keeps_channels_apart() {
+        let resampler = Resampler::new(48000, 44100);
+        let input: Vec<f32> = (0..4800).flat_map(|_| vec![1., 0.]).collect();
+        let output = resampler.process(&input, 2);
+        assert!(output.iter().skip(1).step_by(2).all(|r| *r == 0.));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::convolution::{ConvolutionError, ConvolutionReverb};
+use crate::freeverb::Freeverb;
+use crate::granular::GranularReverb;
+use crate::hybrid::HybridReverb;
+use crate::plate::PlateReverb;
+use crate::sdn::SdnReverb;
+use crate::spring::SpringReverb;
+use crate::utils::Sample;
+use crate::velvet::VelvetReverb;
+use crate::FDNReverb;
+
+/// Description of a parameter of a reverb engine, in the unit its setter expects.
+#[derive(Copy, Clone, Debug)]
+pub struct ParameterInfo {
+    pub name: &'static str,
+    pub default: f32,
+    pub min: f32,
+    pub max: f32,
+    pub unit: &'static str,
+}
+
+// Parameters that all the engines have.
+pub const PRE_DELAY: ParameterInfo = ParameterInfo {
+    name: "pre-delay",
+    default: 0.,
+    min: 0.,
+    max: 150.,
+    unit: "ms",
+};
+pub const WIDTH: ParameterInfo = ParameterInfo {
+    name: "width",
+    default: 1.,
+    min: 0.,
+    max: 2.,
+    unit: "",
+};
+pub const DRY_WET: ParameterInfo = ParameterInfo {
+    name: "dry/wet",
+    default: 0.3,
+    min: 0.,
+    max: 1.,
+    unit: "",
+};
+
+/// Common interface of all the reverb engines, so that they can be swapped and compared
+/// through a single code path.
+pub trait Reverb: Send {
+    fn name(&self) -> &'static str;
+    /// Mono input, interleaved stereo output.
+    fn process(&mut self, input: &[f32], output: &mut [f32]);
+    /// Interleaved stereo input, interleaved stereo output.
+    fn process_stereo(&mut self, input: &[f32], output: &mut [f32]);
+    /// Clear all the internal state, without touching the parameters.
+    fn reset(&mut self);
+    /// Number of frames after the end of the input after which the output can be considered
+    /// silent.
+    fn tail_size(&self) -> isize;
+    fn sample_rate(&self) -> f32;
+    /// Reconfigure the engine for a new sample rate, keeping its parameters. This resets the
+    /// engine.
+    fn set_sample_rate(&mut self, sample_rate: f32);
+    /// Finish applying the parameter changes that are too expensive for the audio thread,
+    /// which `set_parameter` leaves to be done in the background. Offline renders call this
+    /// before processing, so that they don't depend on timing.
+    fn settle(&mut self) {}
+    fn parameters(&self) -> &'static [ParameterInfo];
+    fn set_parameter(&mut self, index: usize, value: f32);
+    /// Current value of a parameter, as the engine keeps it after clamping. 0 for an index out
+    /// of range.
+    fn parameter(&self, index: usize) -> f32;
+
+    fn parameter_index(&self, name: &str) -> Option<usize> {
+        self.parameters().iter().position(|p| p.name == name)
+    }
+}
+
+/// Set the parameters of `to` to the values they have in `from`, an engine of the same kind,
+/// in the order of the table.
+pub fn copy_parameters(from: &dyn Reverb, to: &mut dyn Reverb) {
+    for index in 0..from.parameters().len() {
+        to.set_parameter(index, from.parameter(index));
+    }
+}
+
+#[derive(Debug)]
+pub enum EngineError {
+    UnknownEngine(String),
+    MissingImpulseResponse,
+    Convolution(ConvolutionError),
+}
+
+impl From<ConvolutionError> for EngineError {
+    fn from(e: ConvolutionError) -> EngineError {
+        EngineError::Convolution(e)
+    }
+}
+
+pub const ENGINES: &[&str] = &[
+    "fdn",
+    "convolution",
+    "hybrid",
+    "plate",
+    "freeverb",
+    "velvet",
+    "spring",
+    "sdn",
+    "granular",
+];
+
+/// Create an engine by name. The convolution-based engines need an impulse response.
+pub fn create(
+    engine: &str,
+    sample_rate: f32,
+    impulse_response: Option<&Sample>,
+) -> Result<Box<dyn Reverb>, EngineError> {
+    match engine {
+        "fdn" => Ok(Box::new(FDNReverb::new(sample_rate))),
+        "convolution" => {
+            let ir = impulse_response.ok_or(EngineError::MissingImpulseResponse)?;
+            let mut reverb = ConvolutionReverb::new(sample_rate);
+            reverb.set_impulse_response(ir)?;
+            Ok(Box::new(reverb))
+        }
+        "hybrid" => {
+            let ir = impulse_response.ok_or(EngineError::MissingImpulseResponse)?;
+            let mut reverb = HybridReverb::new(sample_rate);
+            reverb.set_impulse_response(ir)?;
+            Ok(Box::new(reverb))
+        }
+        "plate" => Ok(Box::new(PlateReverb::new(sample_rate))),
+        "freeverb" => Ok(Box::new(Freeverb::new(sample_rate))),
+        "velvet" => Ok(Box::new(VelvetReverb::new(sample_rate))),
+        "spring" => Ok(Box::new(SpringReverb::new(sample_rate))),
+        "sdn" => Ok(Box::new(SdnReverb::new(sample_rate))),
+        "granular" => Ok(Box::new(GranularReverb::new(sample_rate))),
+        _ => Err(EngineError::UnknownEngine(engine.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parameters_read_back() {
+        for engine in ENGINES {
+            let mut reverb = match create(engine, 44100., None) {
+                Ok(reverb) => reverb,
+                Err(EngineError::MissingImpulseResponse) => continue,
+                Err(e) => panic!("{:?}", e),
+            };
+            for (index, p) in reverb.parameters().iter().enumerate() {
+                for value in &[p.min, p.max, p.default] {
+                    reverb.set_parameter(index, *value);
+                    assert_eq!(reverb.parameter(index), *value, "{} {}", engine, p.name);
+                }
+            }
+        }
+    }
+    #[test]
+    fn sample_rate_changes_keep_the_parameters() {
+        for engine in ENGINES {
+            let mut reverb = match create(engine, 44100., None) {
+                Ok(reverb) => reverb,
+                Err(EngineError::MissingImpulseResponse) => continue,
+                Err(e) => panic!("{:?}", e),
+            };
+            let count = reverb.parameters().len();
+            for (index, p) in reverb.parameters().iter().enumerate() {
+                reverb.set_parameter(index, (p.min + p.max) / 2.);
+            }
+            let before: Vec<f32> = (0..count).map(|i| reverb.parameter(i)).collect();
+            reverb.set_sample_rate(48000.);
+            let after: Vec<f32> = (0..count).map(|i| reverb.parameter(i)).collect();
+            assert_eq!(before, after, "{}", engine);
+        }
+    }
+}
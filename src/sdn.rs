@@ -0,0 +1,465 @@
+use crate::delay_line::DelayLine;
+use crate::onepolelowpass::OnePoleLowPass;
+use crate::reverb::{copy_parameters, ParameterInfo, Reverb, DRY_WET, PRE_DELAY, WIDTH};
+use crate::utils::{clamp, isotropic_scattering, matrix_vector_multiply_into, stereo_output};
+
+const SPEED_OF_SOUND: f32 = 343.;
+const WALLS: usize = 6;
+// Each node is connected to all the other nodes.
+const NEIGHBOURS: usize = WALLS - 1;
+const MAX_DIMENSION: f32 = 50.;
+// Distance between the two ears of the listener, along the width of the room.
+const EAR_SPACING: f32 = 0.2;
+
+const PARAMETERS: &[ParameterInfo] = &[
+    PRE_DELAY,
+    ParameterInfo {
+        name: "room width",
+        default: 8.,
+        min: 1.,
+        max: MAX_DIMENSION,
+        unit: "m",
+    },
+    ParameterInfo {
+        name: "room depth",
+        default: 6.,
+        min: 1.,
+        max: MAX_DIMENSION,
+        unit: "m",
+    },
+    ParameterInfo {
+        name: "room height",
+        default: 3.,
+        min: 1.,
+        max: MAX_DIMENSION,
+        unit: "m",
+    },
+    ParameterInfo {
+        name: "source x",
+        default: 2.,
+        min: 0.,
+        max: MAX_DIMENSION,
+        unit: "m",
+    },
+    ParameterInfo {
+        name: "source y",
+        default: 2.,
+        min: 0.,
+        max: MAX_DIMENSION,
+        unit: "m",
+    },
+    ParameterInfo {
+        name: "source z",
+        default: 1.5,
+        min: 0.,
+        max: MAX_DIMENSION,
+        unit: "m",
+    },
+    ParameterInfo {
+        name: "listener x",
+        default: 5.5,
+        min: 0.,
+        max: MAX_DIMENSION,
+        unit: "m",
+    },
+    ParameterInfo {
+        name: "listener y",
+        default: 3.5,
+        min: 0.,
+        max: MAX_DIMENSION,
+        unit: "m",
+    },
+    ParameterInfo {
+        name: "listener z",
+        default: 1.7,
+        min: 0.,
+        max: MAX_DIMENSION,
+        unit: "m",
+    },
+    ParameterInfo {
+        name: "wall absorption",
+        default: 0.2,
+        min: 0.01,
+        max: 1.,
+        unit: "",
+    },
+    ParameterInfo {
+        name: "damping",
+        default: 8000.,
+        min: 100.,
+        max: 20000.,
+        unit: "Hz",
+    },
+    WIDTH,
+    DRY_WET,
+];
+
+fn distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(a, b)| (a - b) * (a - b))
+        .sum::<f32>()
+        .sqrt()
+}
+
+// Spherical spreading, not amplifying closer than a meter.
+fn attenuation(distance: f32) -> f32 {
+    1. / distance.max(1.)
+}
+
+// Index of the `neighbour`-th node connected to `node`, and the other way around.
+fn neighbour(node: usize, index: usize) -> usize {
+    if index < node {
+        index
+    } else {
+        index + 1
+    }
+}
+fn neighbour_index(node: usize, neighbour: usize) -> usize {
+    if neighbour < node {
+        neighbour
+    } else {
+        neighbour - 1
+    }
+}
+
+/// Scattering delay network model of a shoebox room (De Sena et al., 2015). There is a node at
+/// the first-order reflection point on each of the six walls: the source feeds each node, the
+/// nodes are connected to each other by delay lines of the length of their distance, and
+/// scatter the incoming waves with an isotropic matrix, attenuated and filtered by the wall.
+/// The listener has two ears, and hears the direct path as well as each node. First-order
+/// reflections are exact, and the higher orders are increasingly approximate.
+pub struct SdnReverb {
+    drywet: f32,
+    width: f32,
+    pre_delay: DelayLine,
+    pre_delay_time: f32,
+    room: [f32; 3],
+    source: [f32; 3],
+    listener: [f32; 3],
+    wall_absorption: f32,
+    damping: f32,
+    scattering: Vec<f32>,
+    // From the source to each node.
+    source_lines: Vec<DelayLine>,
+    source_gains: [f32; WALLS],
+    // From each node to each of its neighbours, at `node * NEIGHBOURS + index`.
+    node_lines: Vec<DelayLine>,
+    wall_filters: Vec<OnePoleLowPass>,
+    // From each node to each ear, at `node * 2 + ear`.
+    ear_lines: Vec<DelayLine>,
+    ear_gains: [f32; WALLS * 2],
+    direct_lines: [DelayLine; 2],
+    direct_gains: [f32; 2],
+    sample_rate: f32,
+}
+
+impl SdnReverb {
+    pub fn new(sample_rate: f32) -> SdnReverb {
+        let mut pre_delay = DelayLine::new((150. * sample_rate / 1000.) as usize);
+        pre_delay.set_duration(0);
+        let longest = (MAX_DIMENSION * 3f32.sqrt() / SPEED_OF_SOUND * sample_rate) as usize + 2;
+        let lines = |count: usize| (0..count).map(|_| DelayLine::new(longest)).collect();
+        let mut reverb = SdnReverb {
+            drywet: 0.3,
+            width: 1.0,
+            pre_delay,
+            pre_delay_time: 0.,
+            room: [8., 6., 3.],
+            source: [2., 2., 1.5],
+            listener: [5.5, 3.5, 1.7],
+            wall_absorption: 0.2,
+            damping: 8000.,
+            scattering: isotropic_scattering(NEIGHBOURS),
+            source_lines: lines(WALLS),
+            source_gains: [0.; WALLS],
+            node_lines: lines(WALLS * NEIGHBOURS),
+            wall_filters: (0..WALLS * NEIGHBOURS)
+                .map(|_| OnePoleLowPass::new(8000., sample_rate))
+                .collect(),
+            ear_lines: lines(WALLS * 2),
+            ear_gains: [0.; WALLS * 2],
+            direct_lines: [DelayLine::new(longest), DelayLine::new(longest)],
+            direct_gains: [0.; 2],
+            sample_rate,
+        };
+        reverb.configure();
+        reverb
+    }
+
+    fn frames(&self, distance: f32) -> usize {
+        std::cmp::max((distance / SPEED_OF_SOUND * self.sample_rate) as usize, 1)
+    }
+
+    // Keep a position a bit away from the walls.
+    fn inside(&self, position: &[f32; 3]) -> [f32; 3] {
+        let mut p = [0.; 3];
+        for i in 0..3 {
+            p[i] = clamp(position[i], 0.01 * self.room[i], 0.99 * self.room[i]);
+        }
+        p
+    }
+
+    fn ears(&self) -> [[f32; 3]; 2] {
+        let listener = self.inside(&self.listener);
+        let mut left = listener;
+        let mut right = listener;
+        left[0] -= EAR_SPACING / 2.;
+        right[0] += EAR_SPACING / 2.;
+        [self.inside(&left), self.inside(&right)]
+    }
+
+    /// Position of the node on `wall`: where the path from the source to the listener,
+    /// reflected on this wall, hits it.
+    fn node_position(&self, wall: usize) -> [f32; 3] {
+        let source = self.inside(&self.source);
+        let listener = self.inside(&self.listener);
+        let axis = wall / 2;
+        let plane = if wall % 2 == 1 { self.room[axis] } else { 0. };
+        let mut image = source;
+        image[axis] = 2. * plane - source[axis];
+        let t = (plane - image[axis]) / (listener[axis] - image[axis]);
+        let mut node = [0.; 3];
+        for i in 0..3 {
+            node[i] = image[i] + t * (listener[i] - image[i]);
+        }
+        node[axis] = plane;
+        node
+    }
+
+    // Set the length and gain of all the lines from the geometry.
+    fn configure(&mut self) {
+        let source = self.inside(&self.source);
+        let ears = self.ears();
+        let nodes: Vec<[f32; 3]> = (0..WALLS).map(|w| self.node_position(w)).collect();
+        for (k, node) in nodes.iter().enumerate() {
+            let to_source = distance(&source, node);
+            let frames = self.frames(to_source);
+            self.source_lines[k].set_duration(frames);
+            self.source_gains[k] = attenuation(to_source);
+            for m in 0..NEIGHBOURS {
+                let frames = self.frames(distance(node, &nodes[neighbour(k, m)]));
+                self.node_lines[k * NEIGHBOURS + m].set_duration(frames);
+            }
+            for (e, ear) in ears.iter().enumerate() {
+                let to_ear = distance(node, ear);
+                let frames = self.frames(to_ear);
+                self.ear_lines[k * 2 + e].set_duration(frames);
+                // Along with the source gain, this makes the attenuation of the first-order
+                // reflection that of its total path.
+                self.ear_gains[k * 2 + e] = 1. / (1. + to_ear / to_source.max(1.));
+            }
+        }
+        for (e, ear) in ears.iter().enumerate() {
+            let direct = distance(&source, ear);
+            let frames = self.frames(direct);
+            self.direct_lines[e].set_duration(frames);
+            self.direct_gains[e] = attenuation(direct);
+        }
+    }
+
+    pub fn set_pre_delay(&mut self, pre_delay: f32) {
+        let pre_delay_frames = (pre_delay * self.sample_rate / 1000.) as usize;
+        self.pre_delay_time = pre_delay;
+        self.pre_delay.set_duration(pre_delay_frames);
+    }
+
+    /// Width, depth and height of the room, in meters.
+    pub fn set_room(&mut self, room: [f32; 3]) {
+        for (r, d) in self.room.iter_mut().zip(room.iter()) {
+            *r = clamp(*d, 1., MAX_DIMENSION);
+        }
+        self.configure();
+    }
+
+    /// Position of the source, in meters from the corner of the room.
+    pub fn set_source(&mut self, position: [f32; 3]) {
+        self.source = position;
+        self.configure();
+    }
+
+    /// Position of the listener, in meters from the corner of the room.
+    pub fn set_listener(&mut self, position: [f32; 3]) {
+        self.listener = position;
+        self.configure();
+    }
+
+    pub fn set_wall_absorption(&mut self, absorption: f32) {
+        self.wall_absorption = clamp(absorption, 0.01, 1.);
+    }
+
+    pub fn set_damping(&mut self, damping: f32) {
+        self.damping = damping;
+        for f in self.wall_filters.iter_mut() {
+            f.set_frequency(damping);
+        }
+    }
+
+    pub fn set_width(&mut self, width: f32) {
+        self.width = width;
+    }
+
+    pub fn set_drywet(&mut self, drywet: f32) {
+        self.drywet = drywet;
+    }
+
+    fn process_frame(&mut self, input: f32) -> (f32, f32) {
+        let mut predelayed = 0.0;
+        self.pre_delay.process(input, &mut predelayed);
+        let reflection = (1. - self.wall_absorption).sqrt();
+
+        let mut waves = [0.; WALLS * NEIGHBOURS];
+        for (w, line) in waves.iter_mut().zip(self.node_lines.iter_mut()) {
+            line.read(w);
+        }
+
+        let mut wet = [0.; 2];
+        for (e, w) in wet.iter_mut().enumerate() {
+            let mut direct = 0.0;
+            self.direct_lines[e].process(predelayed, &mut direct);
+            *w = direct * self.direct_gains[e];
+        }
+
+        let mut incoming = [0.; NEIGHBOURS];
+        let mut outgoing = [0.; NEIGHBOURS];
+        for k in 0..WALLS {
+            let mut from_source = 0.0;
+            self.source_lines[k].process(predelayed, &mut from_source);
+            // Half of the source pressure goes into each incoming wave.
+            from_source *= self.source_gains[k] / 2.;
+            for (m, i) in incoming.iter_mut().enumerate() {
+                let j = neighbour(k, m);
+                *i = waves[j * NEIGHBOURS + neighbour_index(j, k)] + from_source;
+            }
+            matrix_vector_multiply_into(&incoming, &self.scattering, &mut outgoing);
+            let mut pressure = 0.;
+            for (m, o) in outgoing.iter().enumerate() {
+                let mut filtered = 0.0;
+                self.wall_filters[k * NEIGHBOURS + m].process(o * reflection, &mut filtered);
+                self.node_lines[k * NEIGHBOURS + m].write(filtered);
+                pressure += filtered;
+            }
+            pressure *= 2. / NEIGHBOURS as f32;
+            for (e, w) in wet.iter_mut().enumerate() {
+                let mut heard = 0.0;
+                self.ear_lines[k * 2 + e].process(pressure, &mut heard);
+                *w += heard * self.ear_gains[k * 2 + e];
+            }
+        }
+        (wet[0], wet[1])
+    }
+
+    pub fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        for (i, o) in input.iter().zip(output.chunks_mut(2)) {
+            let (wet_l, wet_r) = self.process_frame(*i);
+            let (l, r) = stereo_output(*i, *i, wet_l, wet_r, self.drywet, self.width);
+            o[0] = l;
+            o[1] = r;
+        }
+    }
+
+    /// The source is mono: it is fed with the mid signal.
+    pub fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        for (i, o) in input.chunks(2).zip(output.chunks_mut(2)) {
+            let (wet_l, wet_r) = self.process_frame((i[0] + i[1]) / 2.);
+            let (l, r) = stereo_output(i[0], i[1], wet_l, wet_r, self.drywet, self.width);
+            o[0] = l;
+            o[1] = r;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pre_delay.reset();
+        self.source_lines.iter_mut().for_each(|l| l.reset());
+        self.node_lines.iter_mut().for_each(|l| l.reset());
+        self.wall_filters.iter_mut().for_each(|f| f.reset());
+        self.ear_lines.iter_mut().for_each(|l| l.reset());
+        self.direct_lines.iter_mut().for_each(|l| l.reset());
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    pub fn tail_size(&self) -> isize {
+        // Sabine's formula.
+        let [x, y, z] = self.room;
+        let volume = x * y * z;
+        let surface = 2. * (x * y + y * z + x * z);
+        let rt60 = 0.161 * volume / (surface * self.wall_absorption);
+        (rt60 * self.sample_rate) as isize
+    }
+}
+
+impl Reverb for SdnReverb {
+    fn name(&self) -> &'static str {
+        "sdn"
+    }
+    fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        SdnReverb::process(self, input, output);
+    }
+    fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        SdnReverb::process_stereo(self, input, output);
+    }
+    fn reset(&mut self) {
+        SdnReverb::reset(self);
+    }
+    fn tail_size(&self) -> isize {
+        SdnReverb::tail_size(self)
+    }
+    fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        let mut fresh = SdnReverb::new(sample_rate);
+        copy_parameters(self, &mut fresh);
+        *self = fresh;
+    }
+    fn parameters(&self) -> &'static [ParameterInfo] {
+        PARAMETERS
+    }
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        match index {
+            0 => self.set_pre_delay(value),
+            1..=3 => {
+                let mut room = self.room;
+                room[index - 1] = value;
+                self.set_room(room);
+            }
+            4..=6 => {
+                let mut source = self.source;
+                source[index - 4] = value;
+                self.set_source(source);
+            }
+            7..=9 => {
+                let mut listener = self.listener;
+                listener[index - 7] = value;
+                self.set_listener(listener);
+            }
+            10 => self.set_wall_absorption(value),
+            11 => self.set_damping(value),
+            12 => self.set_width(value),
+            13 => self.set_drywet(value),
+            _ => {}
+        }
+    }
+    fn parameter(&self, index: usize) -> f32 {
+        match index {
+            0 => self.pre_delay_time,
+            1..=3 => self.room[index - 1],
+            4..=6 => self.source[index - 4],
+            7..=9 => self.listener[index - 7],
+            10 => self.wall_absorption,
+            11 => self.damping,
+            12 => self.width,
+            13 => self.drywet,
+            _ => 0.,
+        }
+    }
+}
+
+impl Default for SdnReverb {
+    fn default() -> Self {
+        SdnReverb::new(44100.)
+    }
+}
@@ -9,6 +9,9 @@ impl Softclip {
     pub fn set_hardness(&mut self, hardness: f32) {
         self.hardness = hardness;
     }
+    pub fn hardness(&self) -> f32 {
+        self.hardness
+    }
     pub fn process(&mut self, input: f32, output: &mut f32) {
         fn fast_tanh(x: f32) -> f32 {
             let x2 = x * x;
@@ -0,0 +1,332 @@
+use crate::allpass::Allpass;
+use crate::delay_line::DelayLine;
+use crate::onepolelowpass::OnePoleLowPass;
+use crate::reverb::{copy_parameters, ParameterInfo, Reverb, DRY_WET, PRE_DELAY, WIDTH};
+use crate::utils::{clamp, stereo_output};
+
+// Largest number of stretched allpasses in the dispersion chain of a spring.
+const STAGES: usize = 100;
+// Coefficient of the stretched allpasses: the higher, the more pronounced the chirps.
+const DISPERSION: f32 = 0.6;
+const MAX_STRETCH: usize = 32;
+// Largest part of a trip along a spring taken by the dispersion chain, the rest is a plain
+// delay: a longer spring disperses more.
+const CHAIN_SHARE: f32 = 0.5;
+// The second spring of the tank is a bit longer, for a stereo image.
+const LENGTH_SPREAD: f32 = 1.13;
+const MIN_LENGTH: f32 = 20.;
+const MAX_LENGTH: f32 = 200.;
+
+const PARAMETERS: &[ParameterInfo] = &[
+    PRE_DELAY,
+    ParameterInfo {
+        name: "tension",
+        default: 4300.,
+        min: 1000.,
+        max: 10000.,
+        unit: "Hz",
+    },
+    ParameterInfo {
+        name: "length",
+        default: 56.,
+        min: MIN_LENGTH,
+        max: MAX_LENGTH,
+        unit: "ms",
+    },
+    ParameterInfo {
+        name: "damping",
+        default: 0.3,
+        min: 0.,
+        max: 1.,
+        unit: "",
+    },
+    WIDTH,
+    DRY_WET,
+];
+
+// One spring: a chain of stretched first-order allpasses, (z^-K - a) / (1 - a z^-K) with
+// a = DISPERSION, in a feedback loop. Below `sample_rate / 2K`, the group delay of the chain
+// decreases with the frequency, from K (1 + a) / (1 - a) to K (1 - a) / (1 + a) per stage, and
+// each trip around the loop smears an impulse into a descending chirp. Above it the spring
+// doesn't carry much, so the loop is lowpassed there.
+struct Spring {
+    dispersion: Vec<Allpass>,
+    // Delay of the stretched allpasses, and number of them in use.
+    stretch: usize,
+    stages: usize,
+    lowpass: OnePoleLowPass,
+    delay: DelayLine,
+    last: f32,
+}
+
+impl Spring {
+    fn new(sample_rate: f32) -> Spring {
+        Spring {
+            dispersion: (0..STAGES)
+                .map(|_| Allpass::from_frames(MAX_STRETCH, DISPERSION))
+                .collect(),
+            stretch: MAX_STRETCH,
+            stages: 0,
+            lowpass: OnePoleLowPass::new(4300., sample_rate),
+            delay: DelayLine::new((MAX_LENGTH * LENGTH_SPREAD * sample_rate / 1000.) as usize),
+            last: 0.,
+        }
+    }
+
+    // This doesn't allocate, and keeps the state of the spring: the allpasses are made for the
+    // largest stretch.
+    fn configure(&mut self, tension: f32, length: f32, sample_rate: f32) {
+        let stretch = clamp(
+            (sample_rate / (2. * tension)).round() as usize,
+            1,
+            MAX_STRETCH,
+        );
+        if stretch != self.stretch {
+            for a in self.dispersion.iter_mut() {
+                a.set_delay(stretch as f32);
+            }
+            self.stretch = stretch;
+        }
+        self.lowpass.set_frequency(tension);
+        // The chain delays low frequencies by K (1 + a) / (1 - a) per stage, which is part of
+        // the length of the spring.
+        let stage_delay = stretch as f32 * (1. + DISPERSION) / (1. - DISPERSION);
+        let frames = length * sample_rate / 1000.;
+        let stages = std::cmp::min(STAGES, (frames * CHAIN_SHARE / stage_delay) as usize);
+        if stages > self.stages {
+            for a in self.dispersion[self.stages..stages].iter_mut() {
+                a.reset();
+            }
+        }
+        self.stages = stages;
+        let delay = frames - stages as f32 * stage_delay;
+        self.delay.set_duration(std::cmp::max(delay as usize, 1));
+    }
+
+    fn reset(&mut self) {
+        self.dispersion.iter_mut().for_each(|a| a.reset());
+        self.lowpass.reset();
+        self.delay.reset();
+        self.last = 0.;
+    }
+
+    fn process(&mut self, input: f32, feedback: f32) -> f32 {
+        let mut x = input + feedback * self.last;
+        for a in self.dispersion[..self.stages].iter_mut() {
+            let mut o = 0.0;
+            a.process(x, &mut o);
+            x = o;
+        }
+        let mut filtered = 0.0;
+        self.lowpass.process(x, &mut filtered);
+        self.delay.process(filtered, &mut self.last);
+        self.last
+    }
+}
+
+/// Spring tank emulation: two springs of slightly different lengths, one per output channel.
+pub struct SpringReverb {
+    drywet: f32,
+    width: f32,
+    pre_delay: DelayLine,
+    pre_delay_time: f32,
+    springs: [Spring; 2],
+    tension: f32,
+    length: f32,
+    damping: f32,
+    sample_rate: f32,
+}
+
+impl SpringReverb {
+    pub fn new(sample_rate: f32) -> SpringReverb {
+        let mut pre_delay = DelayLine::new((150. * sample_rate / 1000.) as usize);
+        pre_delay.set_duration(0);
+        let mut reverb = SpringReverb {
+            drywet: 0.3,
+            width: 1.0,
+            pre_delay,
+            pre_delay_time: 0.,
+            springs: [Spring::new(sample_rate), Spring::new(sample_rate)],
+            tension: 4300.,
+            length: 56.,
+            damping: 0.3,
+            sample_rate,
+        };
+        reverb.configure();
+        reverb
+    }
+
+    fn configure(&mut self) {
+        let (tension, length, sample_rate) = (self.tension, self.length, self.sample_rate);
+        self.springs[0].configure(tension, length, sample_rate);
+        self.springs[1].configure(tension, length * LENGTH_SPREAD, sample_rate);
+    }
+
+    fn feedback(&self) -> f32 {
+        0.98 * (1. - self.damping)
+    }
+
+    pub fn set_pre_delay(&mut self, pre_delay: f32) {
+        let pre_delay_frames = (pre_delay * self.sample_rate / 1000.) as usize;
+        self.pre_delay_time = pre_delay;
+        self.pre_delay.set_duration(pre_delay_frames);
+    }
+
+    /// Frequency under which the springs disperse, in Hz: a tighter spring chirps higher.
+    pub fn set_tension(&mut self, tension: f32) {
+        self.tension = tension;
+        self.configure();
+    }
+
+    /// Time for a trip back and forth along the springs, in ms.
+    pub fn set_length(&mut self, length: f32) {
+        self.length = clamp(length, MIN_LENGTH, MAX_LENGTH);
+        self.configure();
+    }
+
+    /// Loss on each trip along the springs.
+    pub fn set_damping(&mut self, damping: f32) {
+        self.damping = clamp(damping, 0., 1.);
+    }
+
+    pub fn set_width(&mut self, width: f32) {
+        self.width = width;
+    }
+
+    pub fn set_drywet(&mut self, drywet: f32) {
+        self.drywet = drywet;
+    }
+
+    fn process_frame(&mut self, input: f32) -> (f32, f32) {
+        let mut predelayed = 0.0;
+        self.pre_delay.process(input, &mut predelayed);
+        let feedback = self.feedback();
+        (
+            self.springs[0].process(predelayed, feedback),
+            self.springs[1].process(predelayed, feedback),
+        )
+    }
+
+    pub fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        for (i, o) in input.iter().zip(output.chunks_mut(2)) {
+            let (wet_l, wet_r) = self.process_frame(*i);
+            let (l, r) = stereo_output(*i, *i, wet_l, wet_r, self.drywet, self.width);
+            o[0] = l;
+            o[1] = r;
+        }
+    }
+
+    /// Both springs are driven with the mid signal, like a tank with a single transducer.
+    pub fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        for (i, o) in input.chunks(2).zip(output.chunks_mut(2)) {
+            let (wet_l, wet_r) = self.process_frame((i[0] + i[1]) / 2.);
+            let (l, r) = stereo_output(i[0], i[1], wet_l, wet_r, self.drywet, self.width);
+            o[0] = l;
+            o[1] = r;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pre_delay.reset();
+        self.springs.iter_mut().for_each(|s| s.reset());
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    pub fn tail_size(&self) -> isize {
+        // 60dB of decay along the longest spring.
+        let longest = self.length * LENGTH_SPREAD * self.sample_rate / 1000.;
+        let feedback = self.feedback();
+        if feedback <= 0. {
+            return longest as isize;
+        }
+        (longest * -3. / feedback.log10()) as isize
+    }
+}
+
+impl Reverb for SpringReverb {
+    fn name(&self) -> &'static str {
+        "spring"
+    }
+    fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        SpringReverb::process(self, input, output);
+    }
+    fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        SpringReverb::process_stereo(self, input, output);
+    }
+    fn reset(&mut self) {
+        SpringReverb::reset(self);
+    }
+    fn tail_size(&self) -> isize {
+        SpringReverb::tail_size(self)
+    }
+    fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        let mut fresh = SpringReverb::new(sample_rate);
+        copy_parameters(self, &mut fresh);
+        *self = fresh;
+    }
+    fn parameters(&self) -> &'static [ParameterInfo] {
+        PARAMETERS
+    }
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        match index {
+            0 => self.set_pre_delay(value),
+            1 => self.set_tension(value),
+            2 => self.set_length(value),
+            3 => self.set_damping(value),
+            4 => self.set_width(value),
+            5 => self.set_drywet(value),
+            _ => {}
+        }
+    }
+    fn parameter(&self, index: usize) -> f32 {
+        match index {
+            0 => self.pre_delay_time,
+            1 => self.tension,
+            2 => self.length,
+            3 => self.damping,
+            4 => self.width,
+            5 => self.drywet,
+            _ => 0.,
+        }
+    }
+}
+
+impl Default for SpringReverb {
+    fn default() -> Self {
+        SpringReverb::new(44100.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Low-frequency delay of a trip around a spring, in ms.
+    fn trip(spring: &Spring, sample_rate: f32) -> f32 {
+        let stage_delay = spring.stretch as f32 * (1. + DISPERSION) / (1. - DISPERSION);
+        let frames = spring.stages as f32 * stage_delay + spring.delay.duration() as f32;
+        frames * 1000. / sample_rate
+    }
+
+    #[test]
+    fn length_sets_the_trip_at_any_tension() {
+        let mut reverb = SpringReverb::new(48000.);
+        for &tension in &[1000., 4300., 10000.] {
+            reverb.set_tension(tension);
+            for &length in &[20., 56., 200.] {
+                reverb.set_length(length);
+                let spring = &reverb.springs[0];
+                assert!((trip(spring, 48000.) - length).abs() < 0.1);
+                assert!(spring.stages > 0);
+            }
+        }
+        reverb.set_length(1.);
+        assert_eq!(reverb.length, MIN_LENGTH);
+    }
+}
@@ -1,11 +1,13 @@
+use crate::resample::Resampler;
+use crate::wav::{is_wav, Cue, Loop, SampleFormat, WavReader, WavSpec, WavWriter};
 use audrey::*;
-use byteorder::{LittleEndian, WriteBytesExt};
 use log::*;
-use std::fs::DirEntry;
-use std::fs::File;
-use std::io::prelude::*;
+use std::fmt;
+use std::fs::{DirEntry, File};
+use std::io::{self, BufReader, Read};
 use std::mem;
 use std::ops::Index;
+use std::path::Path;
 
 pub fn clamp<T>(v: T, lower_bound: T, higher_bound: T) -> T
 where
@@ -31,78 +33,250 @@ where
     }
 }
 
+/// Mix a dry and a wet stereo signal, and scale the side signal of the result by `width`.
+pub fn stereo_output(
+    dry_l: f32,
+    dry_r: f32,
+    wet_l: f32,
+    wet_r: f32,
+    drywet: f32,
+    width: f32,
+) -> (f32, f32) {
+    let l = dry_l * (1.0 - drywet) + drywet * wet_l;
+    let r = dry_r * (1.0 - drywet) + drywet * wet_r;
+
+    let mid = (l + r) / 2.;
+    let side = (l - r) / 2. * width;
+
+    (mid + side, mid - side)
+}
+
+/// Writes interleaved 16-bit samples to a WAV file. Use `wav::WavWriter` to stream, or to write
+/// other sample formats.
 pub fn dump_wav(
     file_name: &str,
     samples: &[i16],
     channel_count: u32,
     sample_rate: u32,
 ) -> Result<(), std::io::Error> {
-    const COUNT: usize = 44;
-    let wav_header = [
-        // RIFF header
-        0x52, 0x49, 0x46, 0x46, 0x00, 0x00, 0x00, 0x00, 0x57, 0x41, 0x56, 0x45,
-        // fmt chunk. We always write 16-bit samples.
-        0x66, 0x6d, 0x74, 0x20, 0x10, 0x00, 0x00, 0x00, 0x01, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
-        0xFF, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0x10, 0x00, // data chunk
-        0x64, 0x61, 0x74, 0x61, 0xFE, 0xFF, 0xFF, 0x7F,
-    ];
-    const CHANNEL_OFFSET: usize = 22;
-    const SAMPLE_RATE_OFFSET: usize = 24;
-    const BLOCK_ALIGN_OFFSET: usize = 32;
-    let mut header = [0 as u8; COUNT];
-    let mut written = 0;
-
-    while written != COUNT {
-        match written {
-            CHANNEL_OFFSET => {
-                (&mut header[CHANNEL_OFFSET..]).write_u16::<LittleEndian>(channel_count as u16)?;
-                written += 2;
-            }
-            SAMPLE_RATE_OFFSET => {
-                (&mut header[SAMPLE_RATE_OFFSET..]).write_u32::<LittleEndian>(sample_rate)?;
-                written += 4;
-            }
-            BLOCK_ALIGN_OFFSET => {
-                (&mut header[BLOCK_ALIGN_OFFSET..])
-                    .write_u16::<LittleEndian>((channel_count * 2) as u16)?;
-                written += 2;
-            }
-            _ => {
-                (&mut header[written..]).write_u8(wav_header[written])?;
-                written += 1;
-            }
+    let spec = WavSpec {
+        channels: channel_count as u16,
+        sample_rate,
+        format: SampleFormat::Int16,
+    };
+    let mut writer = WavWriter::create(file_name, spec)?;
+    // Without dither, the samples go through the quantizer unchanged.
+    if let Some(quantizer) = writer.quantizer_mut() {
+        quantizer.set_dither(false);
+    }
+    for i in samples.iter() {
+        writer.write_sample(*i as f32 / i16::MAX as f32)?;
+    }
+    writer.finalize()
+}
+
+#[derive(Debug)]
+pub enum SampleError {
+    Io(io::Error),
+    /// The file is not in a format that can be decoded, or is corrupted.
+    Format(String),
+}
+
+impl fmt::Display for SampleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SampleError::Io(e) => write!(f, "{}", e),
+            SampleError::Format(message) => write!(f, "{}", message),
         }
     }
+}
 
-    let mut file = File::create(file_name)?;
-    file.write_all(&header)?;
-    for i in samples.iter() {
-        file.write_i16::<LittleEndian>(*i)?;
+impl std::error::Error for SampleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SampleError::Io(e) => Some(e),
+            SampleError::Format(_) => None,
+        }
+    }
+}
+
+// The WAV reader reports malformed files as invalid data.
+impl From<io::Error> for SampleError {
+    fn from(e: io::Error) -> SampleError {
+        match e.kind() {
+            io::ErrorKind::InvalidData => SampleError::Format(e.to_string()),
+            _ => SampleError::Io(e),
+        }
     }
-    Ok(())
 }
 
+/// What is known about an audio file before decoding it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleInfo {
+    pub channels: u32,
+    pub rate: u32,
+    /// Significant bits per sample, for uncompressed files.
+    pub bits: Option<u16>,
+    /// Length in frames, when the header has it.
+    pub frames: Option<u64>,
+}
+
+enum Decoder {
+    Wav(Box<WavReader<BufReader<File>>>),
+    Other(read::BufFileReader),
+    // audrey's sample iterators drop the rest of the block they decoded along with them, so the
+    // other formats are decoded whole on the first read, and then read from memory.
+    Decoded { samples: Vec<f32>, position: usize },
+}
+
+/// Streaming reader of an audio file, that decodes it block by block. WAV files are parsed
+/// here, the other formats are decoded by audrey.
+pub struct SampleReader {
+    decoder: Decoder,
+    info: SampleInfo,
+}
+
+impl SampleReader {
+    pub fn open(path: &Path) -> Result<SampleReader, SampleError> {
+        let mut header = [0; 12];
+        let read = File::open(path)?.read(&mut header)?;
+        if is_wav(&header[..read]) {
+            let reader = WavReader::open(path)?;
+            let info = reader.info();
+            return Ok(SampleReader {
+                decoder: Decoder::Wav(Box::new(reader)),
+                info: SampleInfo {
+                    channels: info.channels as u32,
+                    rate: info.sample_rate,
+                    bits: Some(info.bits),
+                    frames: Some(info.frames),
+                },
+            });
+        }
+        SampleReader::decode(path)
+    }
+
+    // Opens a file for audrey to decode.
+    fn decode(path: &Path) -> Result<SampleReader, SampleError> {
+        let reader = open(path).map_err(|e| match e {
+            read::ReadError::Io(e) => SampleError::Io(e),
+            e => SampleError::Format(e.to_string()),
+        })?;
+        let description = reader.description();
+        if description.channel_count() == 0 {
+            return Err(SampleError::Format("no channels".to_string()));
+        }
+        Ok(SampleReader {
+            decoder: Decoder::Other(reader),
+            info: SampleInfo {
+                channels: description.channel_count(),
+                rate: description.sample_rate(),
+                bits: None,
+                frames: None,
+            },
+        })
+    }
+
+    pub fn info(&self) -> SampleInfo {
+        self.info
+    }
+
+    /// Loop points of the file, only WAV files have them.
+    pub fn loops(&self) -> &[Loop] {
+        match self.decoder {
+            Decoder::Wav(ref reader) => reader.loops(),
+            _ => &[],
+        }
+    }
+
+    /// Cue markers of the file, only WAV files have them.
+    pub fn cues(&self) -> &[Cue] {
+        match self.decoder {
+            Decoder::Wav(ref reader) => reader.cues(),
+            _ => &[],
+        }
+    }
+
+    /// Reads interleaved samples into `output`, up to its length rounded down to whole frames.
+    /// Returns the number of samples read, zero at the end of the file.
+    pub fn read(&mut self, output: &mut [f32]) -> Result<usize, SampleError> {
+        let channels = self.info.channels as usize;
+        let wanted = output.len() / channels * channels;
+        match self.decoder {
+            Decoder::Wav(ref mut reader) => Ok(reader.read(&mut output[..wanted])?),
+            Decoder::Other(ref mut reader) => {
+                let samples = reader
+                    .samples::<f32>()
+                    .collect::<Result<Vec<f32>, _>>()
+                    .map_err(|e| SampleError::Format(e.to_string()))?;
+                self.decoder = Decoder::Decoded {
+                    samples,
+                    position: 0,
+                };
+                self.read(output)
+            }
+            Decoder::Decoded {
+                ref samples,
+                ref mut position,
+            } => {
+                let left = (samples.len() - *position) / channels * channels;
+                let count = std::cmp::min(wanted, left);
+                output[..count].copy_from_slice(&samples[*position..*position + count]);
+                *position += count;
+                Ok(count)
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Sample {
     name: String,
     channels: u32,
     rate: u32,
     data: Vec<f32>,
+    loops: Vec<Loop>,
+    cues: Vec<Cue>,
 }
 
 impl Sample {
     pub fn new(path: &DirEntry) -> Sample {
-        info!("Loading {:?}...", path.path());
-        let mut file = open(&path.path()).unwrap();
-        let desc = file.description();
-        let data: Vec<f32> = file.samples().map(Result::unwrap).collect::<Vec<_>>();
+        Sample::from_path(&path.path())
+    }
+    /// Load a file, panicking if it can't be loaded. See `Sample::load`.
+    pub fn from_path(path: &Path) -> Sample {
+        Sample::load(path).unwrap_or_else(|e| panic!("can't load {}: {}", path.display(), e))
+    }
+    /// Reads the header of a file, without decoding it.
+    pub fn info(path: &Path) -> Result<SampleInfo, SampleError> {
+        Ok(SampleReader::open(path)?.info())
+    }
+    pub fn load(path: &Path) -> Result<Sample, SampleError> {
+        info!("Loading {:?}...", path);
+        let mut reader = SampleReader::open(path)?;
+        let info = reader.info();
+        let loops = reader.loops().to_vec();
+        let cues = reader.cues().to_vec();
+        let mut data =
+            Vec::with_capacity(info.frames.unwrap_or(0) as usize * info.channels as usize);
+        let mut block = vec![0.0; 4096 * info.channels as usize];
+        loop {
+            let read = reader.read(&mut block)?;
+            if read == 0 {
+                break;
+            }
+            data.extend_from_slice(&block[..read]);
+        }
         let s = Sample {
-            name: path.path().to_str().unwrap().to_string(),
-            channels: desc.channel_count(),
-            rate: desc.sample_rate(),
+            name: path.to_string_lossy().into_owned(),
+            channels: info.channels,
+            rate: info.rate,
             data,
+            loops,
+            cues,
         };
 
-        println!(
+        info!(
             "Loaded file: {} channels: {}, duration: {}, rate: {}",
             s.name(),
             s.channels(),
@@ -110,7 +284,41 @@ impl Sample {
             s.rate()
         );
 
-        return s;
+        Ok(s)
+    }
+    /// Copy of the sample at another rate.
+    pub fn resampled(&self, rate: u32) -> Sample {
+        if rate == self.rate {
+            return self.clone();
+        }
+        info!("Resampling {} from {} to {}", self.name, self.rate, rate);
+        // Markers are moved to the nearest frame at the new rate.
+        let position = |frame: usize| {
+            ((frame as u64 * rate as u64 + self.rate as u64 / 2) / self.rate as u64) as usize
+        };
+        Sample {
+            name: self.name.clone(),
+            channels: self.channels,
+            rate,
+            data: Resampler::new(self.rate, rate).process(&self.data, self.channels as usize),
+            loops: self
+                .loops
+                .iter()
+                .map(|l| Loop {
+                    start: position(l.start),
+                    end: position(l.end),
+                })
+                .filter(|l| l.start < l.end)
+                .collect(),
+            cues: self
+                .cues
+                .iter()
+                .map(|c| Cue {
+                    position: position(c.position),
+                    label: c.label.clone(),
+                })
+                .collect(),
+        }
     }
     pub fn channels(&self) -> u32 {
         self.channels
@@ -127,6 +335,23 @@ impl Sample {
     pub fn name(&self) -> &str {
         &self.name
     }
+    /// Loops of the file (`smpl` chunk), in frames.
+    pub fn loops(&self) -> &[Loop] {
+        &self.loops
+    }
+    /// Markers of the file (`cue ` chunk), in frames, by position.
+    pub fn cues(&self) -> &[Cue] {
+        &self.cues
+    }
+    /// Deinterleaved copy of one channel.
+    pub fn channel(&self, channel: usize) -> Vec<f32> {
+        self.data
+            .iter()
+            .skip(channel)
+            .step_by(self.channels as usize)
+            .cloned()
+            .collect()
+    }
     pub fn slice(&self, start: usize, size: usize) -> &[f32] {
         let mut real_size = size;
         if start + size >= self.data.len() {
@@ -188,9 +413,9 @@ pub fn coprime_with_progression(start: u64, factor: f32, count: usize) -> Vec<u6
             current += 1;
         }
         for i in series.iter() {
-            println!("testing {} and {}", current, *i);
+            debug!("testing {} and {}", current, *i);
             if (((current as f32) / *i as f32) - 2.0).abs() > 0.05 {
-                println!("{} is too close to {}, nudging", current, *i);
+                debug!("{} is too close to {}, nudging", current, *i);
                 current = (current as f32 * 1.05) as u64;
             }
         }
@@ -200,6 +425,15 @@ pub fn coprime_with_progression(start: u64, factor: f32, count: usize) -> Vec<u6
     return series;
 }
 
+/// Gain to apply once per trip around a loop of `loop_frames` frames so that it decays by 60dB
+/// in `rt60` seconds.
+pub fn loop_gain_for_rt60(rt60: f32, loop_frames: f32, sample_rate: f32) -> f32 {
+    if rt60 <= 0. {
+        return 0.;
+    }
+    10.0f32.powf(-3. * loop_frames / (sample_rate * rt60))
+}
+
 // http://en.wikipedia.org/wiki/Hadamard_matrix sylvester construction
 pub fn hadamard(order: usize) -> Result<Vec<f32>, ()> {
     fn idx(x: usize, y: usize, w: usize) -> usize {
@@ -236,3 +470,116 @@ pub fn matrix_vector_multiply(v: &[f32; 4], m: &[f32; 16]) -> [f32; 4] {
     }
     r
 }
+
+/// Isotropic scattering matrix of a junction of `order` waveguides, `2/order - I`: lossless,
+/// and it scatters an incoming wave evenly in all the other directions.
+pub fn isotropic_scattering(order: usize) -> Vec<f32> {
+    let mut mat = vec![2. / order as f32; order * order];
+    for i in 0..order {
+        mat[i * order + i] -= 1.;
+    }
+    mat
+}
+
+/// Multiply `v` by the square matrix `m`, stored row-major, into `output`.
+pub fn matrix_vector_multiply_into(v: &[f32], m: &[f32], output: &mut [f32]) {
+    let order = v.len();
+    for i in 0..order {
+        output[i] = 0.;
+        for j in 0..order {
+            output[i] += m[i * order + j] * v[j];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resampling_moves_markers() {
+        let sample = Sample {
+            name: "loop".to_string(),
+            channels: 2,
+            rate: 44100,
+            data: vec![0.; 2 * 44100],
+            loops: vec![Loop {
+                start: 11025,
+                end: 33075,
+            }],
+            cues: vec![
+                Cue {
+                    position: 441,
+                    label: Some("attack".to_string()),
+                },
+                Cue {
+                    position: 44100,
+                    label: None,
+                },
+            ],
+        };
+        let resampled = sample.resampled(48000);
+        assert_eq!(resampled.frames(), 48000);
+        assert_eq!(
+            resampled.loops(),
+            &[Loop {
+                start: 12000,
+                end: 36000
+            }]
+        );
+        assert_eq!(resampled.cues()[0].position, 480);
+        assert_eq!(resampled.cues()[0].label.as_deref(), Some("attack"));
+        assert_eq!(resampled.cues()[1].position, 48000);
+
+        // Rounded to the nearest frame.
+        let resampled = sample.resampled(22050);
+        assert_eq!(resampled.cues()[0].position, 221);
+        assert_eq!(resampled.loops()[0].start, 5513);
+    }
+    #[test]
+    fn dump_wav_keeps_the_samples() {
+        let samples = [i16::MIN, -12345, -1, 0, 1, i16::MAX];
+        let path = std::env::temp_dir().join("fdn-reverb-dump-wav.wav");
+        let name = path.to_str().unwrap();
+        dump_wav(name, &samples, 2, 44100).unwrap();
+        let file = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let data: Vec<i16> = file[file.len() - 12..]
+            .chunks(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(data, samples);
+    }
+    // Reads a whole file in blocks of `block` samples.
+    fn read_in_blocks(mut reader: SampleReader, block: usize) -> Vec<f32> {
+        let mut samples = Vec::new();
+        let mut buffer = vec![0.; block];
+        loop {
+            let read = reader.read(&mut buffer).unwrap();
+            if read == 0 {
+                return samples;
+            }
+            samples.extend_from_slice(&buffer[..read]);
+        }
+    }
+
+    #[test]
+    fn decoded_files_read_the_same_in_any_block_size() {
+        let path = std::env::temp_dir().join("fdn-reverb-decoded-blocks.wav");
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            format: SampleFormat::Float32,
+        };
+        let written: Vec<f32> = (0..2000).map(|i| (i as f32 * 0.01).sin()).collect();
+        let mut writer = WavWriter::create(path.to_str().unwrap(), spec).unwrap();
+        writer.write_samples(&written).unwrap();
+        writer.finalize().unwrap();
+
+        let whole = read_in_blocks(SampleReader::decode(&path).unwrap(), 4096);
+        let small = read_in_blocks(SampleReader::decode(&path).unwrap(), 7);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(whole, written);
+        assert_eq!(small, written);
+    }
+}
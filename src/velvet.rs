@@ -0,0 +1,521 @@
+use crate::delay_line::DelayLine;
+use crate::onepolelowpass::OnePoleLowPass;
+use crate::random::Random;
+use crate::reverb::{copy_parameters, ParameterInfo, Reverb, DRY_WET, PRE_DELAY, WIDTH};
+use crate::softclip::Softclip;
+use crate::utils::{clamp, stereo_output};
+
+// Longest segment, in seconds.
+const MAX_SEGMENT: f32 = 1.;
+
+const PARAMETERS: &[ParameterInfo] = &[
+    PRE_DELAY,
+    ParameterInfo {
+        name: "absorption",
+        default: 5000.,
+        min: 100.,
+        max: 20000.,
+        unit: "Hz",
+    },
+    ParameterInfo {
+        name: "size",
+        default: 20.,
+        min: 1.,
+        max: 1000.,
+        unit: "m",
+    },
+    ParameterInfo {
+        name: "decay",
+        default: 0.3,
+        min: 0.,
+        max: 1.25,
+        unit: "",
+    },
+    ParameterInfo {
+        name: "density",
+        default: 2000.,
+        min: 200.,
+        max: 4000.,
+        unit: "pulses/s",
+    },
+    WIDTH,
+    DRY_WET,
+];
+
+/// Sparse FIR filter made of a velvet noise sequence: one pulse of random sign at a random
+/// position in each grid cell of `sample_rate / density` frames. The pulses are weighted by an
+/// exponential envelope that reaches `end_gain` at the end of the sequence.
+#[derive(Default)]
+pub struct VelvetNoise {
+    // (delay in frames, gain)
+    pulses: Vec<(usize, f32)>,
+    length: usize,
+    grid: f32,
+    count: usize,
+    normalization: f32,
+    // Envelope of the next pulse, and its ratio from a grid cell to the next.
+    envelope: f32,
+    decay: f32,
+}
+
+impl VelvetNoise {
+    pub fn new() -> VelvetNoise {
+        VelvetNoise::default()
+    }
+
+    /// Generate a new sequence of `length` frames.
+    pub fn generate(
+        &mut self,
+        random: &mut Random,
+        length: usize,
+        density: f32,
+        sample_rate: f32,
+        end_gain: f32,
+    ) {
+        self.start(length, density, sample_rate, end_gain);
+        self.extend(random, length);
+    }
+
+    /// Start a new sequence of `length` frames, without pulses: `extend` adds them, so that a
+    /// sequence can be generated a few pulses at a time.
+    pub fn start(&mut self, length: usize, density: f32, sample_rate: f32, end_gain: f32) {
+        self.length = std::cmp::max(length, 1);
+        self.grid = clamp(sample_rate / density, 1., self.length as f32);
+        self.count = (self.length as f32 / self.grid) as usize;
+        self.normalization = 1. / (self.count as f32).sqrt();
+        // The envelope is taken in the middle of each grid cell.
+        self.decay = end_gain.powf(self.grid / self.length as f32);
+        self.envelope = self.decay.sqrt();
+        self.pulses.clear();
+        self.pulses.reserve(self.count);
+    }
+
+    /// Add the pulses of the grid cells that start in the first `frames` frames of the
+    /// sequence.
+    pub fn extend(&mut self, random: &mut Random, frames: usize) {
+        while self.pulses.len() < self.count && self.pulses.len() as f32 * self.grid < frames as f32
+        {
+            let position = (self.pulses.len() as f32 + random.next_f32()) * self.grid;
+            let delay = std::cmp::min(position as usize, self.length - 1);
+            let sign = if random.next_f32() < 0.5 { -1. } else { 1. };
+            self.pulses
+                .push((delay, sign * self.envelope * self.normalization));
+            self.envelope *= self.decay;
+        }
+    }
+
+    /// Filter the signal that has been written to `history`, the last frame written having a
+    /// delay of zero. `history` has to be longer than the sequence.
+    pub fn filter(&self, history: &DelayLine) -> f32 {
+        self.pulses
+            .iter()
+            .map(|(delay, gain)| gain * history.read_at(*delay))
+            .sum()
+    }
+}
+
+/// A flat velvet noise sequence, for cheap decorrelation of a signal: use different seeds for
+/// each channel.
+pub struct Decorrelator {
+    velvet: VelvetNoise,
+    history: DelayLine,
+}
+
+impl Decorrelator {
+    /// `length` is in seconds, `density` in pulses per second.
+    pub fn new(length: f32, density: f32, seed: u32, sample_rate: f32) -> Decorrelator {
+        let frames = (length * sample_rate) as usize;
+        let mut velvet = VelvetNoise::new();
+        velvet.generate(&mut Random::new(seed), frames, density, sample_rate, 1.);
+        Decorrelator {
+            velvet,
+            history: DelayLine::new(frames + 2),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.history.reset();
+    }
+
+    pub fn process(&mut self, input: f32, output: &mut f32) {
+        self.history.write(input);
+        *output = self.velvet.filter(&self.history);
+    }
+}
+
+// A damped feedback delay, of the length of a segment, filtered by velvet noise. A new sequence
+// is used for each trip around the loop, and crossfaded with the previous one, so that the
+// echoes never repeat: the result is an exponentially decaying velvet noise, at the cost of two
+// sparse sequences per frame. The envelope of the sequences continues the decay of the loop
+// over a segment. The sequence of the next trip is generated during the current one, a pulse
+// at a time.
+struct VelvetChannel {
+    delay: DelayLine,
+    lowpass: OnePoleLowPass,
+    random: Random,
+    current: VelvetNoise,
+    next: VelvetNoise,
+    upcoming: VelvetNoise,
+    position: usize,
+    length: usize,
+    density: f32,
+    end_gain: f32,
+    sample_rate: f32,
+}
+
+impl VelvetChannel {
+    fn new(seed: u32, sample_rate: f32) -> VelvetChannel {
+        VelvetChannel {
+            delay: DelayLine::new((MAX_SEGMENT * sample_rate) as usize + 2),
+            lowpass: OnePoleLowPass::new(5000., sample_rate),
+            random: Random::new(seed),
+            current: VelvetNoise::new(),
+            next: VelvetNoise::new(),
+            upcoming: VelvetNoise::new(),
+            position: 0,
+            length: 1,
+            density: 2000.,
+            end_gain: 1.,
+            sample_rate,
+        }
+    }
+
+    fn configure(&mut self, length: usize, density: f32, end_gain: f32) {
+        self.length = length;
+        self.density = density;
+        self.end_gain = end_gain;
+        self.delay.set_duration(length);
+        self.position = 0;
+        self.current.generate(
+            &mut self.random,
+            length,
+            density,
+            self.sample_rate,
+            end_gain,
+        );
+        self.next.generate(
+            &mut self.random,
+            length,
+            density,
+            self.sample_rate,
+            end_gain,
+        );
+        self.upcoming
+            .start(length, density, self.sample_rate, end_gain);
+    }
+
+    fn reset(&mut self) {
+        self.delay.reset();
+        self.lowpass.reset();
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, softclip: &mut Softclip) -> f32 {
+        let mut delayed = 0.0;
+        let mut clipped = 0.0;
+        let mut filtered = 0.0;
+        self.delay.read(&mut delayed);
+        softclip.process(delayed, &mut clipped);
+        self.lowpass.process(clipped, &mut filtered);
+        self.delay.write(input + feedback * filtered);
+
+        // Equal power crossfade, the sequences are uncorrelated.
+        let fade = self.position as f32 / self.length as f32 * std::f32::consts::FRAC_PI_2;
+        let wet = fade.cos() * self.current.filter(&self.delay)
+            + fade.sin() * self.next.filter(&self.delay);
+
+        self.position += 1;
+        self.upcoming.extend(&mut self.random, self.position);
+        if self.position == self.length {
+            self.position = 0;
+            std::mem::swap(&mut self.current, &mut self.next);
+            std::mem::swap(&mut self.next, &mut self.upcoming);
+            self.upcoming
+                .start(self.length, self.density, self.sample_rate, self.end_gain);
+        }
+        wet
+    }
+}
+
+/// Reverb tail made of velvet noise, with the same size, decay and absorption semantics as
+/// `FDNReverb`.
+pub struct VelvetReverb {
+    drywet: f32,
+    width: f32,
+    pre_delay: DelayLine,
+    pre_delay_time: f32,
+    channels: [VelvetChannel; 2],
+    softclip: Softclip,
+    size: f32,
+    decay: f32,
+    absorbtion: f32,
+    density: f32,
+    sample_rate: f32,
+}
+
+impl VelvetReverb {
+    pub fn new(sample_rate: f32) -> VelvetReverb {
+        let mut pre_delay = DelayLine::new((150. * sample_rate / 1000.) as usize);
+        pre_delay.set_duration(0);
+        let mut reverb = VelvetReverb {
+            drywet: 0.3,
+            width: 1.0,
+            pre_delay,
+            pre_delay_time: 0.,
+            channels: [
+                VelvetChannel::new(1, sample_rate),
+                VelvetChannel::new(2, sample_rate),
+            ],
+            softclip: Softclip::new(1.),
+            size: 20.,
+            decay: 0.3,
+            absorbtion: 5000.,
+            density: 2000.,
+            sample_rate,
+        };
+        reverb.configure();
+        reverb
+    }
+
+    // Length of a segment: about the average length of the delay lines of an `FDNReverb` of the
+    // same size.
+    fn segment_length(&self) -> usize {
+        let seconds = clamp(self.size / 330. / 4., 0.002, MAX_SEGMENT);
+        (seconds * self.sample_rate) as usize
+    }
+
+    // Like in the FDN, the gain of a trip is twice the decay, and the softclip keeps the
+    // loop bounded above 0.5.
+    fn feedback(&self) -> f32 {
+        2. * self.decay
+    }
+
+    fn configure(&mut self) {
+        let length = self.segment_length();
+        let end_gain = self.feedback().min(1.);
+        let density = self.density;
+        for c in self.channels.iter_mut() {
+            c.configure(length, density, end_gain);
+        }
+    }
+
+    pub fn set_pre_delay(&mut self, pre_delay: f32) {
+        let pre_delay_frames = (pre_delay * self.sample_rate / 1000.) as usize;
+        self.pre_delay_time = pre_delay;
+        self.pre_delay.set_duration(pre_delay_frames);
+    }
+
+    pub fn set_size(&mut self, size: f32) {
+        self.size = size;
+        self.configure();
+    }
+
+    pub fn set_decay(&mut self, decay: f32) {
+        self.decay = decay;
+        self.configure();
+    }
+
+    pub fn set_absorbtion(&mut self, abs: f32) {
+        self.absorbtion = abs;
+        for c in self.channels.iter_mut() {
+            c.lowpass.set_frequency(abs);
+        }
+    }
+
+    pub fn set_density(&mut self, density: f32) {
+        self.density = density;
+        self.configure();
+    }
+
+    pub fn set_width(&mut self, width: f32) {
+        self.width = width;
+    }
+
+    pub fn set_drywet(&mut self, drywet: f32) {
+        self.drywet = drywet;
+    }
+
+    fn process_frame(&mut self, l: f32, r: f32) -> (f32, f32) {
+        let feedback = self.feedback();
+        let softclip = &mut self.softclip;
+        let [left, right] = &mut self.channels;
+        (
+            left.process(l, feedback, softclip),
+            right.process(r, feedback, softclip),
+        )
+    }
+
+    pub fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        for (i, o) in input.iter().zip(output.chunks_mut(2)) {
+            let mut predelayed = 0.0;
+            self.pre_delay.process(*i, &mut predelayed);
+            let (wet_l, wet_r) = self.process_frame(predelayed, predelayed);
+            let (l, r) = stereo_output(*i, *i, wet_l, wet_r, self.drywet, self.width);
+            o[0] = l;
+            o[1] = r;
+        }
+    }
+
+    /// Both channels are fed with the mid signal.
+    pub fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        for (i, o) in input.chunks(2).zip(output.chunks_mut(2)) {
+            let mut predelayed = 0.0;
+            self.pre_delay.process((i[0] + i[1]) / 2., &mut predelayed);
+            let (wet_l, wet_r) = self.process_frame(predelayed, predelayed);
+            let (l, r) = stereo_output(i[0], i[1], wet_l, wet_r, self.drywet, self.width);
+            o[0] = l;
+            o[1] = r;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pre_delay.reset();
+        self.channels.iter_mut().for_each(|c| c.reset());
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    pub fn tail_size(&self) -> isize {
+        let gain = self.feedback();
+        if gain >= 1. {
+            // arbitrary, the softclip keeps it going
+            return (self.sample_rate * self.decay * 10.) as isize;
+        }
+        if gain <= 0. {
+            return self.segment_length() as isize;
+        }
+        (self.segment_length() as f32 * -3. / gain.log10()) as isize
+    }
+}
+
+impl Reverb for VelvetReverb {
+    fn name(&self) -> &'static str {
+        "velvet"
+    }
+    fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        VelvetReverb::process(self, input, output);
+    }
+    fn process_stereo(&mut self, input: &[f32], output: &mut [f32]) {
+        VelvetReverb::process_stereo(self, input, output);
+    }
+    fn reset(&mut self) {
+        VelvetReverb::reset(self);
+    }
+    fn tail_size(&self) -> isize {
+        VelvetReverb::tail_size(self)
+    }
+    fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        let mut fresh = VelvetReverb::new(sample_rate);
+        copy_parameters(self, &mut fresh);
+        *self = fresh;
+    }
+    fn parameters(&self) -> &'static [ParameterInfo] {
+        PARAMETERS
+    }
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        match index {
+            0 => self.set_pre_delay(value),
+            1 => self.set_absorbtion(value),
+            2 => self.set_size(value),
+            3 => self.set_decay(value),
+            4 => self.set_density(value),
+            5 => self.set_width(value),
+            6 => self.set_drywet(value),
+            _ => {}
+        }
+    }
+    fn parameter(&self, index: usize) -> f32 {
+        match index {
+            0 => self.pre_delay_time,
+            1 => self.absorbtion,
+            2 => self.size,
+            3 => self.decay,
+            4 => self.density,
+            5 => self.width,
+            6 => self.drywet,
+            _ => 0.,
+        }
+    }
+}
+
+impl Default for VelvetReverb {
+    fn default() -> Self {
+        VelvetReverb::new(44100.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(length: usize, density: f32, end_gain: f32) -> VelvetNoise {
+        let mut velvet = VelvetNoise::new();
+        velvet.generate(&mut Random::new(1), length, density, 44100., end_gain);
+        velvet
+    }
+
+    #[test]
+    fn one_pulse_per_grid_cell() {
+        let velvet = sequence(44100, 2000., 1.);
+        assert_eq!(velvet.pulses.len(), 2000);
+        let grid = 44100. / 2000.;
+        for (m, (delay, gain)) in velvet.pulses.iter().enumerate() {
+            assert!(*delay as f32 >= (m as f32 * grid).floor());
+            assert!((*delay as f32) < (m + 1) as f32 * grid);
+            assert_eq!(gain.abs(), 1. / 2000f32.sqrt());
+        }
+    }
+
+    #[test]
+    fn envelope_reaches_the_end_gain() {
+        let velvet = sequence(44100, 2000., 0.01);
+        let gains: Vec<f32> = velvet.pulses.iter().map(|(_, g)| g.abs()).collect();
+        assert!(gains.windows(2).all(|w| w[1] < w[0]));
+        let first = gains[0] * 2000f32.sqrt();
+        let last = gains[gains.len() - 1] * 2000f32.sqrt();
+        assert!((first - 0.01f32.powf(0.5 / 2000.)).abs() < 1e-4);
+        assert!((last / 0.01 - 1.).abs() < 0.01);
+    }
+
+    #[test]
+    fn extending_gives_the_same_sequence() {
+        let whole = sequence(4410, 1000., 0.5);
+        let mut random = Random::new(1);
+        let mut velvet = VelvetNoise::new();
+        velvet.start(4410, 1000., 44100., 0.5);
+        for frames in 1..=4410 {
+            velvet.extend(&mut random, frames);
+        }
+        assert_eq!(velvet.pulses, whole.pulses);
+    }
+
+    #[test]
+    fn tail_size_is_the_time_to_decay_by_60db() {
+        let mut reverb = VelvetReverb::new(44100.);
+        reverb.set_drywet(1.);
+        reverb.set_decay(0.3);
+        let tail = reverb.tail_size() as usize;
+        let window = 4410;
+        let mut input = vec![0.0; tail + window];
+        input[0] = 1.;
+        let mut output = vec![0.0; input.len() * 2];
+        reverb.process(&input, &mut output);
+        // Level of a window starting at `frame`, relative to the start, in dB.
+        let level = |frame: usize| {
+            let energy = |f: usize| {
+                output[f * 2..(f + window) * 2]
+                    .iter()
+                    .map(|s| s * s)
+                    .sum::<f32>()
+            };
+            10. * (energy(frame) / energy(0)).log10()
+        };
+        // The absorption makes it decay a bit faster than that.
+        assert!(level(tail) < -60.);
+        assert!(level(tail / 2) > -60.);
+    }
+}
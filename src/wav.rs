@@ -0,0 +1,869 @@
+use crate::quantize::Quantizer;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+// The sub format GUIDs of the extensible header are the format tag followed by this.
+const GUID_SUFFIX: [u8; 14] = [
+    0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleFormat {
+    Int16,
+    Int24,
+    Int32,
+    Float32,
+}
+
+impl SampleFormat {
+    pub fn bits(self) -> u16 {
+        match self {
+            SampleFormat::Int16 => 16,
+            SampleFormat::Int24 => 24,
+            SampleFormat::Int32 => 32,
+            SampleFormat::Float32 => 32,
+        }
+    }
+
+    pub fn bytes(self) -> u16 {
+        self.bits() / 8
+    }
+
+    pub fn is_float(self) -> bool {
+        self == SampleFormat::Float32
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WavSpec {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub format: SampleFormat,
+}
+
+impl WavSpec {
+    pub fn block_align(&self) -> u16 {
+        self.channels * self.format.bytes()
+    }
+
+    pub fn byte_rate(&self) -> u32 {
+        self.sample_rate * self.block_align() as u32
+    }
+
+    // Microsoft requires the extensible header for more than two channels, and for more than 16
+    // bits per integer sample.
+    fn extensible(&self) -> bool {
+        self.channels > 2 || (!self.format.is_float() && self.format.bits() > 16)
+    }
+}
+
+/// Streaming WAV file writer. The header is written with empty sizes when the writer is created,
+/// the samples are written as they come, and the sizes are patched when the writer is finalized
+/// (or dropped). Float samples written to integer files are quantized with TPDF dither.
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    spec: WavSpec,
+    quantizer: Option<Quantizer>,
+    data_bytes: u64,
+    // Offsets of the sizes to patch when finalizing.
+    fact_offset: Option<u64>,
+    data_offset: u64,
+    finalized: bool,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, spec: WavSpec) -> io::Result<Self> {
+        WavWriter::new(BufWriter::new(File::create(path)?), spec)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, spec: WavSpec) -> io::Result<Self> {
+        if spec.channels == 0 || spec.sample_rate == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a WAV file needs at least one channel and a non-zero sample rate",
+            ));
+        }
+        let format_tag = if spec.format.is_float() {
+            WAVE_FORMAT_IEEE_FLOAT
+        } else {
+            WAVE_FORMAT_PCM
+        };
+
+        let mut header = Vec::with_capacity(80);
+        header.write_all(b"RIFF")?;
+        header.write_u32::<LittleEndian>(0)?;
+        header.write_all(b"WAVE")?;
+
+        header.write_all(b"fmt ")?;
+        let fmt_size = if spec.extensible() {
+            40
+        } else if spec.format.is_float() {
+            18
+        } else {
+            16
+        };
+        header.write_u32::<LittleEndian>(fmt_size)?;
+        header.write_u16::<LittleEndian>(if spec.extensible() {
+            WAVE_FORMAT_EXTENSIBLE
+        } else {
+            format_tag
+        })?;
+        header.write_u16::<LittleEndian>(spec.channels)?;
+        header.write_u32::<LittleEndian>(spec.sample_rate)?;
+        header.write_u32::<LittleEndian>(spec.byte_rate())?;
+        header.write_u16::<LittleEndian>(spec.block_align())?;
+        header.write_u16::<LittleEndian>(spec.format.bits())?;
+        if spec.extensible() {
+            header.write_u16::<LittleEndian>(22)?;
+            header.write_u16::<LittleEndian>(spec.format.bits())?;
+            header.write_u32::<LittleEndian>(channel_mask(spec.channels))?;
+            header.write_u16::<LittleEndian>(format_tag)?;
+            header.write_all(&GUID_SUFFIX)?;
+        } else if spec.format.is_float() {
+            header.write_u16::<LittleEndian>(0)?;
+        }
+
+        // Non-PCM files carry the number of frames in a fact chunk.
+        let mut fact_offset = None;
+        if spec.format.is_float() {
+            header.write_all(b"fact")?;
+            header.write_u32::<LittleEndian>(4)?;
+            fact_offset = Some(header.len() as u64);
+            header.write_u32::<LittleEndian>(0)?;
+        }
+
+        header.write_all(b"data")?;
+        let data_offset = header.len() as u64;
+        header.write_u32::<LittleEndian>(0)?;
+
+        writer.write_all(&header)?;
+
+        let quantizer = if spec.format.is_float() {
+            None
+        } else {
+            Some(Quantizer::new(spec.format.bits(), spec.channels as usize))
+        };
+
+        Ok(WavWriter {
+            writer,
+            spec,
+            quantizer,
+            data_bytes: 0,
+            fact_offset,
+            data_offset,
+            finalized: false,
+        })
+    }
+
+    pub fn spec(&self) -> WavSpec {
+        self.spec
+    }
+
+    pub fn frames_written(&self) -> u64 {
+        self.data_bytes / self.spec.block_align() as u64
+    }
+
+    /// The quantizer used to write float samples to integer files, to set the dither and noise
+    /// shaping, or to know how many samples clipped.
+    pub fn quantizer_mut(&mut self) -> Option<&mut Quantizer> {
+        self.quantizer.as_mut()
+    }
+
+    /// Writes a sample in [-1, 1]. Samples are interleaved: the channel is deduced from the
+    /// number of samples written so far.
+    pub fn write_sample(&mut self, sample: f32) -> io::Result<()> {
+        let bytes = self.spec.format.bytes() as u64;
+        let channel = (self.data_bytes / bytes) as usize % self.spec.channels as usize;
+        match self.quantizer.as_mut() {
+            Some(quantizer) => {
+                let value = quantizer.process_sample(channel, sample);
+                self.write_int(value)?;
+            }
+            None => self.writer.write_f32::<LittleEndian>(sample)?,
+        }
+        // Only count what made it to the file, so that the header stays consistent after an
+        // error.
+        self.data_bytes += bytes;
+        Ok(())
+    }
+
+    /// Writes interleaved samples in [-1, 1].
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for s in samples {
+            self.write_sample(*s)?;
+        }
+        Ok(())
+    }
+
+    // Writes a sample from the quantizer, which keeps it in the range of the bit depth.
+    fn write_int(&mut self, sample: i32) -> io::Result<()> {
+        match self.spec.format {
+            SampleFormat::Int16 => self.writer.write_i16::<LittleEndian>(sample as i16),
+            SampleFormat::Int24 => self.writer.write_i24::<LittleEndian>(sample),
+            SampleFormat::Int32 => self.writer.write_i32::<LittleEndian>(sample),
+            SampleFormat::Float32 => unreachable!("float files are written without a quantizer"),
+        }
+    }
+
+    /// Writes the sizes in the header, and flushes the file.
+    pub fn finalize(mut self) -> io::Result<()> {
+        self.patch_header()
+    }
+
+    fn patch_header(&mut self) -> io::Result<()> {
+        if self.finalized {
+            return Ok(());
+        }
+        self.finalized = true;
+        // Chunks are padded to an even size, which a 24-bit mono file with an odd number of
+        // frames isn't.
+        if self.data_bytes % 2 == 1 {
+            self.writer.write_u8(0)?;
+        }
+        let padded = self.data_bytes + self.data_bytes % 2;
+        let riff_size = self.data_offset + 4 + padded - 8;
+        if riff_size > u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "too much data for a WAV file",
+            ));
+        }
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_u32::<LittleEndian>(riff_size as u32)?;
+        if let Some(offset) = self.fact_offset {
+            let frames = self.frames_written() as u32;
+            self.writer.seek(SeekFrom::Start(offset))?;
+            self.writer.write_u32::<LittleEndian>(frames)?;
+        }
+        self.writer.seek(SeekFrom::Start(self.data_offset))?;
+        self.writer
+            .write_u32::<LittleEndian>(self.data_bytes as u32)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        // Errors can't be reported from here: call `finalize` to get them.
+        let _ = self.patch_header();
+    }
+}
+
+// The first `channels` speakers of the standard layout.
+fn channel_mask(channels: u16) -> u32 {
+    if channels >= 18 {
+        0x3FFFF
+    } else {
+        (1 << channels) - 1
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Whether a file starts like a WAV file.
+pub fn is_wav(header: &[u8]) -> bool {
+    header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"WAVE"
+}
+
+/// What the header of a WAV file says.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WavInfo {
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// Significant bits per sample.
+    pub bits: u16,
+    pub float: bool,
+    pub frames: u64,
+}
+
+/// A sustain loop, in frames, from the `smpl` chunk. `end` is exclusive.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Loop {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A marker, in frames, from the `cue ` chunk, with its label if the file has one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cue {
+    pub position: usize,
+    pub label: Option<String>,
+}
+
+// The metadata is optional: a truncated chunk is ignored rather than an error.
+fn optional<T>(parsed: io::Result<Vec<T>>) -> io::Result<Vec<T>> {
+    match parsed {
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(Vec::new()),
+        parsed => parsed,
+    }
+}
+
+// The loops of a `smpl` chunk: nine header fields, then 24 bytes per loop.
+fn parse_smpl(mut chunk: &[u8]) -> io::Result<Vec<Loop>> {
+    let mut header = [0; 9];
+    chunk.read_u32_into::<LittleEndian>(&mut header)?;
+    let mut loops = Vec::new();
+    for _ in 0..header[7] {
+        let mut fields = [0; 6];
+        chunk.read_u32_into::<LittleEndian>(&mut fields)?;
+        // The end is the last frame played.
+        let (start, end) = (fields[2] as usize, fields[3] as usize + 1);
+        if start < end {
+            loops.push(Loop { start, end });
+        }
+    }
+    Ok(loops)
+}
+
+// The identifiers and positions of a `cue ` chunk, 24 bytes per cue point.
+fn parse_cue(mut chunk: &[u8]) -> io::Result<Vec<(u32, Cue)>> {
+    let count = chunk.read_u32::<LittleEndian>()?;
+    let mut cues = Vec::new();
+    for _ in 0..count {
+        let mut fields = [0; 6];
+        chunk.read_u32_into::<LittleEndian>(&mut fields)?;
+        let cue = Cue {
+            position: fields[5] as usize,
+            label: None,
+        };
+        cues.push((fields[0], cue));
+    }
+    Ok(cues)
+}
+
+// The `labl` sub-chunks of a `LIST` chunk of type `adtl`: a cue identifier, then the text.
+fn parse_labels(mut chunk: &[u8]) -> io::Result<Vec<(u32, String)>> {
+    let mut labels = Vec::new();
+    let mut list_type = [0; 4];
+    chunk.read_exact(&mut list_type)?;
+    if &list_type != b"adtl" {
+        return Ok(labels);
+    }
+    while chunk.len() >= 8 {
+        let mut id = [0; 4];
+        chunk.read_exact(&mut id)?;
+        let size = chunk.read_u32::<LittleEndian>()? as usize;
+        let padded = std::cmp::min(size + size % 2, chunk.len());
+        let (body, rest) = chunk.split_at(padded);
+        if &id == b"labl" && size >= 4 && body.len() >= 4 {
+            let text = &body[4..std::cmp::min(size, body.len())];
+            let text = text.split(|b| *b == 0).next().unwrap_or(&[]);
+            let cue = (&body[..4]).read_u32::<LittleEndian>()?;
+            labels.push((cue, String::from_utf8_lossy(text).into_owned()));
+        }
+        chunk = rest;
+    }
+    Ok(labels)
+}
+
+/// Streaming WAV file reader: the header is parsed when the reader is created, and the samples
+/// are decoded block by block. Reads 8, 16, 24 and 32-bit integer and 32 and 64-bit float
+/// files, with or without the extensible header.
+pub struct WavReader<R: Read + Seek> {
+    reader: R,
+    info: WavInfo,
+    // Bytes per sample in the file.
+    bytes: usize,
+    // Bytes of samples left in the data chunk.
+    remaining: u64,
+    buffer: Vec<u8>,
+    loops: Vec<Loop>,
+    cues: Vec<Cue>,
+}
+
+impl WavReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        WavReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> WavReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 12];
+        reader.read_exact(&mut header)?;
+        if !is_wav(&header) {
+            return Err(invalid("not a WAV file"));
+        }
+        let riff_size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let finalized = riff_size != 0 && riff_size != u32::MAX;
+        let length = reader.seek(SeekFrom::End(0))?;
+        let mut position = reader.seek(SeekFrom::Start(12))?;
+
+        // (format tag, channels, rate, bits per sample in the file, significant bits)
+        let mut format = None;
+        let mut data = None;
+        let mut loops = Vec::new();
+        let mut cues = Vec::new();
+        let mut labels = Vec::new();
+        while position + 8 <= length {
+            let mut id = [0; 4];
+            reader.read_exact(&mut id)?;
+            let mut size = reader.read_u32::<LittleEndian>()? as u64;
+            let start = position + 8;
+            match &id {
+                b"fmt " => {
+                    if size < 16 {
+                        return Err(invalid("fmt chunk too short"));
+                    }
+                    let mut tag = reader.read_u16::<LittleEndian>()?;
+                    let channels = reader.read_u16::<LittleEndian>()?;
+                    let rate = reader.read_u32::<LittleEndian>()?;
+                    let _byte_rate = reader.read_u32::<LittleEndian>()?;
+                    let _block_align = reader.read_u16::<LittleEndian>()?;
+                    let container = reader.read_u16::<LittleEndian>()?;
+                    let mut bits = container;
+                    if tag == WAVE_FORMAT_EXTENSIBLE {
+                        if size < 40 {
+                            return Err(invalid("extensible fmt chunk too short"));
+                        }
+                        let _extension_size = reader.read_u16::<LittleEndian>()?;
+                        bits = reader.read_u16::<LittleEndian>()?;
+                        let _channel_mask = reader.read_u32::<LittleEndian>()?;
+                        tag = reader.read_u16::<LittleEndian>()?;
+                    }
+                    format = Some((tag, channels, rate, container, bits));
+                }
+                b"data" => {
+                    // Files that were not finalized have sizes of 0 (`WavWriter` before
+                    // `finalize`), -1 (streamed), or too large: read to the end.
+                    let unknown = (size == 0 && !finalized) || size == u32::MAX as u64;
+                    if unknown || size > length - start {
+                        size = length - start;
+                    }
+                    data = Some((start, size));
+                }
+                b"smpl" | b"cue " | b"LIST" => {
+                    let mut chunk = vec![0; std::cmp::min(size, length - start) as usize];
+                    reader.read_exact(&mut chunk)?;
+                    match &id {
+                        b"smpl" => loops = optional(parse_smpl(&chunk))?,
+                        b"cue " => cues = optional(parse_cue(&chunk))?,
+                        _ => labels.extend(optional(parse_labels(&chunk))?),
+                    }
+                }
+                _ => {}
+            }
+            // Chunks are padded to an even size.
+            position = start + size + size % 2;
+            reader.seek(SeekFrom::Start(position))?;
+        }
+
+        let (tag, channels, sample_rate, container, bits) =
+            format.ok_or_else(|| invalid("no fmt chunk"))?;
+        let (start, size) = data.ok_or_else(|| invalid("no data chunk"))?;
+        let float = match (tag, container) {
+            (WAVE_FORMAT_PCM, 8) | (WAVE_FORMAT_PCM, 16) => false,
+            (WAVE_FORMAT_PCM, 24) | (WAVE_FORMAT_PCM, 32) => false,
+            (WAVE_FORMAT_IEEE_FLOAT, 32) | (WAVE_FORMAT_IEEE_FLOAT, 64) => true,
+            _ => {
+                return Err(invalid(&format!(
+                    "unsupported format {:#x} with {} bits per sample",
+                    tag, container
+                )))
+            }
+        };
+        if channels == 0 || sample_rate == 0 {
+            return Err(invalid("no channels, or a sample rate of zero"));
+        }
+        let bytes = container as usize / 8;
+        let frame_bytes = (bytes * channels as usize) as u64;
+        let frames = size / frame_bytes;
+        loops.retain(|l| l.end as u64 <= frames);
+        let mut cues: Vec<Cue> = cues
+            .into_iter()
+            .map(|(id, mut cue)| {
+                cue.label = labels
+                    .iter()
+                    .find(|(label_id, _)| *label_id == id)
+                    .map(|(_, text)| text.clone());
+                cue
+            })
+            .filter(|cue| cue.position as u64 <= frames)
+            .collect();
+        cues.sort_by_key(|cue| cue.position);
+        reader.seek(SeekFrom::Start(start))?;
+        Ok(WavReader {
+            reader,
+            info: WavInfo {
+                channels,
+                sample_rate,
+                bits,
+                float,
+                frames,
+            },
+            bytes,
+            remaining: size - size % frame_bytes,
+            buffer: Vec::new(),
+            loops,
+            cues,
+        })
+    }
+
+    pub fn info(&self) -> WavInfo {
+        self.info
+    }
+
+    /// Loops of the `smpl` chunk that are within the file, in the order of the file.
+    pub fn loops(&self) -> &[Loop] {
+        &self.loops
+    }
+
+    /// Cue points within the file, by position.
+    pub fn cues(&self) -> &[Cue] {
+        &self.cues
+    }
+
+    /// Reads interleaved samples in [-1, 1] into `output`, up to its length rounded down to whole
+    /// frames. Returns the number of samples read, zero at the end of the file.
+    pub fn read(&mut self, output: &mut [f32]) -> io::Result<usize> {
+        let channels = self.info.channels as usize;
+        let samples = std::cmp::min(
+            output.len() / channels * channels,
+            (self.remaining / self.bytes as u64) as usize,
+        );
+        self.buffer.resize(samples * self.bytes, 0);
+        self.reader.read_exact(&mut self.buffer)?;
+        self.remaining -= self.buffer.len() as u64;
+
+        let chunks = self.buffer.chunks(self.bytes);
+        for (o, b) in output.iter_mut().zip(chunks) {
+            *o = match (self.info.float, self.bytes) {
+                (false, 1) => (b[0] as f32 - 128.) / 128.,
+                (false, 2) => i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.,
+                (false, 3) => {
+                    (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / (1 << 23) as f32
+                }
+                (false, _) => {
+                    i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / (1u32 << 31) as f32
+                }
+                (true, 4) => f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+                (true, _) => {
+                    f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32
+                }
+            };
+        }
+        Ok(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn write(spec: WavSpec, samples: &[f32]) -> Vec<u8> {
+        let mut file = Cursor::new(Vec::new());
+        let mut writer = WavWriter::new(&mut file, spec).unwrap();
+        writer.write_samples(samples).unwrap();
+        writer.finalize().unwrap();
+        file.into_inner()
+    }
+
+    fn read(file: Vec<u8>) -> (WavInfo, Vec<f32>) {
+        let mut reader = WavReader::new(Cursor::new(file)).unwrap();
+        let info = reader.info();
+        let mut samples = vec![0.; 1024];
+        let read = reader.read(&mut samples).unwrap();
+        samples.truncate(read);
+        (info, samples)
+    }
+
+    fn u16_at(file: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([file[offset], file[offset + 1]])
+    }
+
+    fn u32_at(file: &[u8], offset: usize) -> u32 {
+        let b = &file[offset..offset + 4];
+        u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+    }
+
+    // The chunks of a file: identifier, offset of the body and size. Checks the RIFF size, and
+    // that the chunks are padded and fill the file.
+    fn chunks(file: &[u8]) -> Vec<([u8; 4], usize, usize)> {
+        assert_eq!(&file[0..4], b"RIFF");
+        assert_eq!(u32_at(file, 4) as usize, file.len() - 8);
+        assert_eq!(&file[8..12], b"WAVE");
+        let mut chunks = Vec::new();
+        let mut position = 12;
+        while position < file.len() {
+            let mut id = [0; 4];
+            id.copy_from_slice(&file[position..position + 4]);
+            let size = u32_at(file, position + 4) as usize;
+            chunks.push((id, position + 8, size));
+            position += 8 + size + size % 2;
+        }
+        assert_eq!(position, file.len());
+        chunks
+    }
+
+    fn spec(channels: u16, format: SampleFormat) -> WavSpec {
+        WavSpec {
+            channels,
+            sample_rate: 44100,
+            format,
+        }
+    }
+
+    #[test]
+    fn pcm_header() {
+        let file = write(spec(2, SampleFormat::Int16), &[0.; 6]);
+        let chunks = chunks(&file);
+        let ids: Vec<&[u8]> = chunks.iter().map(|(id, _, _)| &id[..]).collect();
+        assert_eq!(ids, vec![b"fmt ", b"data"]);
+        let (_, fmt, size) = chunks[0];
+        assert_eq!(size, 16);
+        assert_eq!(u16_at(&file, fmt), WAVE_FORMAT_PCM);
+        assert_eq!(u16_at(&file, fmt + 2), 2);
+        assert_eq!(u32_at(&file, fmt + 4), 44100);
+        assert_eq!(u32_at(&file, fmt + 8), 44100 * 4);
+        assert_eq!(u16_at(&file, fmt + 12), 4);
+        assert_eq!(u16_at(&file, fmt + 14), 16);
+        assert_eq!(chunks[1].2, 12);
+    }
+
+    #[test]
+    fn extensible_header() {
+        for &(channels, format, mask) in &[
+            (2, SampleFormat::Int24, 0x3),
+            (3, SampleFormat::Int16, 0x7),
+            (3, SampleFormat::Float32, 0x7),
+        ] {
+            let file = write(spec(channels, format), &[0.; 6]);
+            let chunks = chunks(&file);
+            let (_, fmt, size) = chunks[0];
+            assert_eq!(size, 40);
+            assert_eq!(u16_at(&file, fmt), WAVE_FORMAT_EXTENSIBLE);
+            assert_eq!(u16_at(&file, fmt + 2), channels);
+            assert_eq!(u16_at(&file, fmt + 12), channels * format.bytes());
+            assert_eq!(u16_at(&file, fmt + 14), format.bits());
+            assert_eq!(u16_at(&file, fmt + 16), 22);
+            assert_eq!(u16_at(&file, fmt + 18), format.bits());
+            assert_eq!(u32_at(&file, fmt + 20), mask);
+            let tag = if format.is_float() {
+                WAVE_FORMAT_IEEE_FLOAT
+            } else {
+                WAVE_FORMAT_PCM
+            };
+            assert_eq!(u16_at(&file, fmt + 24), tag);
+            assert_eq!(&file[fmt + 26..fmt + 40], &GUID_SUFFIX);
+        }
+    }
+
+    #[test]
+    fn float_header_has_a_fact_chunk() {
+        let file = write(spec(2, SampleFormat::Float32), &[0.; 10]);
+        let chunks = chunks(&file);
+        let ids: Vec<&[u8]> = chunks.iter().map(|(id, _, _)| &id[..]).collect();
+        assert_eq!(ids, vec![b"fmt ", b"fact", b"data"]);
+        let (_, fmt, size) = chunks[0];
+        assert_eq!(size, 18);
+        assert_eq!(u16_at(&file, fmt), WAVE_FORMAT_IEEE_FLOAT);
+        assert_eq!(u16_at(&file, fmt + 16), 0);
+        assert_eq!(chunks[1].2, 4);
+        assert_eq!(u32_at(&file, chunks[1].1), 5);
+        assert_eq!(chunks[2].2, 40);
+    }
+
+    #[test]
+    fn odd_data_is_padded() {
+        let file = write(spec(1, SampleFormat::Int24), &[0.; 3]);
+        let chunks = chunks(&file);
+        let (_, data, size) = chunks[1];
+        assert_eq!(size, 9);
+        assert_eq!(file.len(), data + 10);
+        assert_eq!(file[data + 9], 0);
+    }
+
+    #[test]
+    fn round_trip() {
+        let samples = [0.5, -0.25, 0., 0.75, -1., 0.125];
+        for &format in &[
+            SampleFormat::Int16,
+            SampleFormat::Int24,
+            SampleFormat::Int32,
+            SampleFormat::Float32,
+        ] {
+            let mut file = Cursor::new(Vec::new());
+            let mut writer = WavWriter::new(&mut file, spec(3, format)).unwrap();
+            if let Some(quantizer) = writer.quantizer_mut() {
+                quantizer.set_dither(false);
+            }
+            writer.write_samples(&samples).unwrap();
+            assert_eq!(writer.frames_written(), 2);
+            writer.finalize().unwrap();
+            let (info, read) = read(file.into_inner());
+            assert_eq!(info.channels, 3);
+            assert_eq!(info.bits, format.bits());
+            assert_eq!(info.float, format.is_float());
+            assert_eq!(info.frames, 2);
+            for (r, s) in read.iter().zip(samples.iter()) {
+                assert!((r - s).abs() <= 1. / 32768., "{:?}: {} {}", format, r, s);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_files_that_were_not_finalized() {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            format: SampleFormat::Float32,
+        };
+        let samples: Vec<f32> = (0..20).map(|i| i as f32 / 20.).collect();
+        let mut file = write(spec, &samples);
+        let data = file.windows(4).position(|w| w == b"data").unwrap();
+        for &size in &[0u32, u32::MAX] {
+            file[4..8].copy_from_slice(&size.to_le_bytes());
+            file[data + 4..data + 8].copy_from_slice(&size.to_le_bytes());
+            let (info, read) = read(file.clone());
+            assert_eq!(info.frames, 10);
+            assert_eq!(read, samples);
+        }
+    }
+
+    fn chunk(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn words(values: &[u32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|v| v.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    // A mono 16-bit file of `frames` frames, with extra chunks after the data.
+    fn wav(frames: usize, chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = b"WAVE".to_vec();
+        let fmt = [1u16, 1, 0x5622, 0, 0xAC44, 0, 2, 16];
+        let fmt: Vec<u8> = fmt.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect();
+        body.extend(chunk(b"fmt ", &fmt));
+        body.extend(chunk(b"data", &vec![0; frames * 2]));
+        for c in chunks {
+            body.extend_from_slice(c);
+        }
+        let mut file = b"RIFF".to_vec();
+        file.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        file.extend(body);
+        file
+    }
+
+    fn smpl(loops: &[(u32, u32)]) -> Vec<u8> {
+        let mut body = words(&[0, 0, 22675, 60, 0, 0, 0, loops.len() as u32, 0]);
+        for (i, (start, end)) in loops.iter().enumerate() {
+            body.extend(words(&[i as u32, 0, *start, *end, 0, 0]));
+        }
+        body
+    }
+
+    fn cue(points: &[(u32, u32)]) -> Vec<u8> {
+        let mut body = words(&[points.len() as u32]);
+        for (id, position) in points {
+            body.extend(words(&[*id, 0]));
+            body.extend_from_slice(b"data");
+            body.extend(words(&[0, 0, *position]));
+        }
+        body
+    }
+
+    fn labels(list_type: &[u8], labels: &[(u32, &str)]) -> Vec<u8> {
+        let mut body = list_type.to_vec();
+        for (id, text) in labels {
+            let mut label = words(&[*id]);
+            label.extend_from_slice(text.as_bytes());
+            label.push(0);
+            body.extend(chunk(b"labl", &label));
+        }
+        body
+    }
+
+    #[test]
+    fn loop_end_is_inclusive() {
+        let loops = parse_smpl(&smpl(&[(100, 199), (50, 50), (300, 299)])).unwrap();
+        assert_eq!(
+            loops,
+            vec![
+                Loop {
+                    start: 100,
+                    end: 200
+                },
+                Loop { start: 50, end: 51 }
+            ]
+        );
+
+        // Loops that go past the end of the file are dropped.
+        let file = wav(1000, &[chunk(b"smpl", &smpl(&[(0, 999), (500, 1000)]))]);
+        let reader = WavReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(
+            reader.loops(),
+            &[Loop {
+                start: 0,
+                end: 1000
+            }]
+        );
+    }
+
+    #[test]
+    fn cues_get_their_labels() {
+        let file = wav(
+            1000,
+            &[
+                chunk(b"cue ", &cue(&[(7, 600), (3, 10), (9, 20)])),
+                chunk(b"LIST", &labels(b"INFO", &[(9, "not a label")])),
+                chunk(b"LIST", &labels(b"adtl", &[(3, "attack"), (7, "odd")])),
+            ],
+        );
+        let reader = WavReader::new(Cursor::new(file)).unwrap();
+        let cues = reader.cues();
+        let positions: Vec<usize> = cues.iter().map(|c| c.position).collect();
+        assert_eq!(positions, vec![10, 20, 600]);
+        assert_eq!(cues[0].label.as_deref(), Some("attack"));
+        assert_eq!(cues[1].label, None);
+        assert_eq!(cues[2].label.as_deref(), Some("odd"));
+    }
+
+    #[test]
+    fn truncated_chunks_are_ignored() {
+        let smpl = smpl(&[(0, 99)]);
+        let cue = cue(&[(1, 10), (2, 20)]);
+        assert_eq!(
+            parse_smpl(&smpl[..40]).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            parse_cue(&cue[..30]).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        let labels = labels(b"adtl", &[(1, "intro")]);
+        let file = wav(
+            100,
+            &[
+                chunk(b"smpl", &smpl[..smpl.len() - 4]),
+                chunk(b"cue ", &cue[..cue.len() - 4]),
+                chunk(b"LIST", &labels[..labels.len() - 4]),
+            ],
+        );
+        let mut reader = WavReader::new(Cursor::new(file)).unwrap();
+        assert!(reader.loops().is_empty());
+        assert!(reader.cues().is_empty());
+        assert_eq!(reader.info().frames, 100);
+        assert_eq!(reader.read(&mut [1.; 200]).unwrap(), 100);
+    }
+}